@@ -0,0 +1,243 @@
+//! Authentication log persisted to flash
+//!
+//! Entries are appended to a `sequential_storage::queue` in their own flash range,
+//! so the log survives reboots. The oldest entry is dropped once the configured
+//! capacity is reached.
+//!
+//! The queue can only be read from the oldest entry on, so a read keeps the
+//! entries just before the requested one in RAM: paging through the log newest
+//! first walks the queue once per `WINDOW` entries, not once per entry.
+
+use crate::clock::DateTime;
+use embedded_storage_async::nor_flash::{MultiwriteNorFlash, NorFlash};
+use crate::println;
+use heapless::Deque;
+use sequential_storage::cache::NoCache;
+use sequential_storage::queue;
+
+/// Flash storage range for the auth log
+/// 32KB (8 pages) directly before the settings range (0x3DE000..0x3E0000)
+const FLASH_RANGE: core::ops::Range<u32> = 0x3D6000..0x3DE000;
+
+/// Default number of retained entries
+pub const DEFAULT_CAPACITY: usize = 200;
+/// Upper bound for the configured capacity
+/// One queue item is ~64 bytes on flash, 7 of the 8 pages hold ~440 items
+pub const MAX_CAPACITY: usize = 400;
+
+/// Serialized entry length (also the size of the `log_entry` characteristic)
+pub const ENTRY_LEN: usize = 55;
+/// Entries written before wall-clock support had no timestamp field
+const ENTRY_LEN_V1: usize = 50;
+/// Entries written before the result code was recorded
+const ENTRY_LEN_V2: usize = 54;
+
+/// Entries kept in RAM from the last walk of the queue
+const WINDOW: usize = 16;
+
+/// Entry flags
+const FLAG_VALID: u8 = 0x01;
+const FLAG_SUCCESS: u8 = 0x02;
+const FLAG_TIME_UNSYNCED: u8 = 0x04;
+
+/// Result byte of an entry without a recorded result
+const RESULT_UNKNOWN: u8 = 0xFF;
+
+#[derive(Copy, Clone)]
+pub struct AuthLogEntry {
+    pub pubkey: [u8; 33],
+    pub uptime_ms: u64,
+    /// Wall-clock time of the attempt, None if the clock was not synced
+    pub time: Option<DateTime>,
+    pub addr: [u8; 6],
+    pub auth_action: u16,
+    pub success: bool,
+    /// `auth_result` code of the attempt, None for entries written before it was recorded
+    pub result: Option<u8>,
+}
+
+impl Default for AuthLogEntry {
+    fn default() -> Self {
+        AuthLogEntry {
+            pubkey: [0; 33],
+            uptime_ms: 0,
+            time: None,
+            addr: [0; 6],
+            auth_action: 0,
+            success: false,
+            result: None,
+        }
+    }
+}
+
+impl AuthLogEntry {
+    /// Serialize to the wire/flash format
+    pub fn to_bytes(&self) -> [u8; ENTRY_LEN] {
+        let mut out = [0u8; ENTRY_LEN];
+        let unix = self.time.and_then(|t| t.to_unix()).unwrap_or(0) as u32;
+        out[0] = FLAG_VALID
+            | if self.success { FLAG_SUCCESS } else { 0 }
+            | if unix == 0 { FLAG_TIME_UNSYNCED } else { 0 };
+        out[1..34].copy_from_slice(&self.pubkey);
+        out[34..42].copy_from_slice(&self.uptime_ms.to_le_bytes());
+        out[42..48].copy_from_slice(&self.addr);
+        out[48..50].copy_from_slice(&self.auth_action.to_le_bytes());
+        out[50..54].copy_from_slice(&unix.to_le_bytes());
+        out[54] = self.result.unwrap_or(RESULT_UNKNOWN);
+        out
+    }
+
+    /// Parse an entry written by `to_bytes`
+    /// Entries from before wall-clock support are read as unsynced, from before
+    /// result codes without a result
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < ENTRY_LEN_V1 || data[0] & FLAG_VALID == 0 {
+            return None;
+        }
+        let time = if data.len() >= ENTRY_LEN_V2 && data[0] & FLAG_TIME_UNSYNCED == 0 {
+            let unix = u32::from_le_bytes(data[50..54].try_into().unwrap());
+            Some(DateTime::from_unix(unix as u64))
+        } else {
+            None
+        };
+        Some(Self {
+            pubkey: data[1..34].try_into().unwrap(),
+            uptime_ms: u64::from_le_bytes(data[34..42].try_into().unwrap()),
+            time,
+            addr: data[42..48].try_into().unwrap(),
+            auth_action: u16::from_le_bytes([data[48], data[49]]),
+            success: data[0] & FLAG_SUCCESS != 0,
+            result: data.get(54).copied().filter(|&r| r != RESULT_UNKNOWN),
+        })
+    }
+}
+
+/// Flash-backed auth log, only the entry count and the last read window are kept in RAM
+pub struct AuthLog {
+    count: usize,
+    capacity: usize,
+    /// Entries at queue positions `window_start..window_start + window.len()`, oldest first
+    window: Deque<Option<AuthLogEntry>, WINDOW>,
+    window_start: usize,
+}
+
+impl AuthLog {
+    /// Open the log, counting the entries already in flash
+    /// Entries above `capacity` (e.g. after lowering it) are dropped, oldest first
+    pub async fn new<S: MultiwriteNorFlash>(flash: &mut S, capacity: usize) -> Self {
+        let capacity = capacity.clamp(1, MAX_CAPACITY);
+        let mut log = Self { count: 0, capacity, window: Deque::new(), window_start: 0 };
+        log.count = log.scan(flash, usize::MAX).await.1;
+        while log.count > log.capacity {
+            if !log.pop_oldest(flash).await {
+                break;
+            }
+        }
+        println!("Auth log: {} entries, capacity {}", log.count, log.capacity);
+        log
+    }
+
+    /// Append an entry, dropping the oldest one if the log is full
+    pub async fn push<S: MultiwriteNorFlash>(
+        &mut self,
+        flash: &mut S,
+        entry: AuthLogEntry,
+    ) -> Result<(), sequential_storage::Error<S::Error>> {
+        if self.count >= self.capacity {
+            self.pop_oldest(flash).await;
+        }
+        let mut cache = NoCache::new();
+        // Overwriting is only a fallback, capacity is bounded to fit the range
+        self.window.clear();
+        queue::push(flash, FLASH_RANGE, &mut cache, &entry.to_bytes(), true).await?;
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Get an entry by index, 0 being the newest
+    pub async fn get<S: NorFlash>(&mut self, flash: &mut S, index: usize) -> Option<AuthLogEntry> {
+        if index >= self.count {
+            return None;
+        }
+        let position = self.count - 1 - index;
+        if let Some(entry) = position.checked_sub(self.window_start).and_then(|i| self.window.iter().nth(i)) {
+            return *entry;
+        }
+        let (entry, seen) = self.scan(flash, position).await;
+        if entry.is_none() && seen < self.count {
+            // Old entries were overwritten by the queue, resync the count
+            self.count = seen;
+        }
+        entry
+    }
+
+    /// Serialized entry for the `log_entry` characteristic, all zeroes if out of range
+    pub async fn entry_bytes<S: NorFlash>(&mut self, flash: &mut S, index: usize) -> [u8; ENTRY_LEN] {
+        match self.get(flash, index).await {
+            Some(entry) => entry.to_bytes(),
+            None => [0u8; ENTRY_LEN],
+        }
+    }
+
+    /// Walk the queue from the oldest entry, keeping the entries up to `position` in the window
+    /// Returns the entry at `position` (if reached) and the number of entries visited
+    async fn scan<S: NorFlash>(&mut self, flash: &mut S, position: usize) -> (Option<AuthLogEntry>, usize) {
+        self.window.clear();
+        let mut cache = NoCache::new();
+        let mut buf = [0u8; 64];
+        let mut iter = match queue::iter(flash, FLASH_RANGE, &mut cache).await {
+            Ok(iter) => iter,
+            Err(reason) => {
+                println!("ERROR: auth log iter failed: {:?}", reason);
+                return (None, 0);
+            }
+        };
+        let mut seen = 0;
+        loop {
+            match iter.next(&mut buf).await {
+                Ok(Some(data)) => {
+                    let entry = AuthLogEntry::from_bytes(&data);
+                    if self.window.is_full() {
+                        self.window.pop_front();
+                    }
+                    let _ = self.window.push_back(entry);
+                    self.window_start = seen + 1 - self.window.len();
+                    if seen == position {
+                        return (entry, seen + 1);
+                    }
+                    seen += 1;
+                }
+                Ok(None) => return (None, seen),
+                Err(reason) => {
+                    println!("ERROR: auth log read failed: {:?}", reason);
+                    return (None, seen);
+                }
+            }
+        }
+    }
+
+    async fn pop_oldest<S: MultiwriteNorFlash>(&mut self, flash: &mut S) -> bool {
+        // Positions shift down by one
+        self.window.clear();
+        let mut cache = NoCache::new();
+        let mut buf = [0u8; 64];
+        match queue::pop(flash, FLASH_RANGE, &mut cache, &mut buf).await {
+            Ok(Some(_)) => {
+                self.count = self.count.saturating_sub(1);
+                true
+            }
+            Ok(None) => {
+                self.count = 0;
+                false
+            }
+            Err(reason) => {
+                println!("ERROR: auth log pop failed: {:?}", reason);
+                false
+            }
+        }
+    }
+}
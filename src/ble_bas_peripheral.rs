@@ -1,18 +1,25 @@
-use crate::keys::KeyStore;
+use crate::authlog::{self, AuthLog, AuthLogEntry};
+use crate::clock::{self, DateTime};
+use crate::faultlog::{self, FaultLog, FaultRecord};
+use crate::fsm::{FAULT_EVENTS, GATE_STATUS};
+use crate::gpi;
+use crate::keys::{AccessDenied, KeyInfo, KeyStore, Validity, KEY_INFO_LEN, LABEL_LEN, NO_SCHEDULE};
+use crate::schedule::{self, Schedule, ScheduleStore};
+use crate::lockout::AuthLimiter;
 use crate::settings::{ConfigStore, MAX_NAME_LEN};
-use crate::types::FsmCommand;
+use crate::types::{DoorMovement, FsmCommand, GateState, GateStatus};
 use core::default::Default;
 use crate::settings::ConfigSlot;
 use core::option::Option;
 use core::result::Result::{self, Err, Ok};
 use ed25519_dalek::{Verifier, VerifyingKey};
 use embassy_futures::join::join;
-use embassy_futures::select::select;
+use embassy_futures::select::{select, select3, Either};
 // use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_sync::channel::Sender;
 use embassy_time::{Instant, Timer};
-use embedded_storage_async::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::{MultiwriteNorFlash, NorFlash};
 use heapless::String;
 use hex_fmt::HexFmt;
 use p256::ecdsa::signature::hazmat::PrehashVerifier;
@@ -27,83 +34,7 @@ const CONNECTIONS_MAX: usize = 2;
 const L2CAP_CHANNELS_MAX: usize = 2; // Signal + att
 
 use esp_println::println;
-const AUTH_LOG_CAP: usize = 100;
-const AUTH_LOG_ENTRY_LEN: usize = 50;
-
-#[derive(Copy, Clone)]
-struct AuthLogEntry {
-    pubkey: [u8; 33],
-    uptime_ms: u64,
-    addr: [u8; 6],
-    auth_action: u16,
-    success: bool,
-}
-impl Default for AuthLogEntry {
-    fn default() -> Self {
-        AuthLogEntry {
-            pubkey: [0; 33],
-            uptime_ms: 0,
-            addr: [0; 6],
-            auth_action: 0,
-            success: false,
-        }
-    }
-}
-
-
-struct AuthLog {
-    entries: [AuthLogEntry; AUTH_LOG_CAP],
-    write_idx: usize,
-    count: usize,
-}
-
-impl AuthLog {
-    fn new() -> Self {
-        Self {
-            entries: [AuthLogEntry::default(); AUTH_LOG_CAP],
-            write_idx: 0,
-            count: 0,
-        }
-    }
-
-    fn push(&mut self, entry: AuthLogEntry) {
-        self.entries[self.write_idx] = entry;
-        self.write_idx = (self.write_idx + 1) % AUTH_LOG_CAP;
-        if self.count < AUTH_LOG_CAP {
-            self.count += 1;
-        }
-    }
-
-    fn count(&self) -> usize {
-        self.count
-    }
 
-    fn get(&self, index: usize) -> Option<AuthLogEntry> {
-        if index >= self.count {
-            return None;
-        }
-        let newest_idx = if self.write_idx == 0 {
-            AUTH_LOG_CAP - 1
-        } else {
-            self.write_idx - 1
-        };
-        let pos = (newest_idx + AUTH_LOG_CAP - index) % AUTH_LOG_CAP;
-        Some(self.entries[pos])
-    }
-
-    fn entry_bytes(&self, index: usize) -> [u8; AUTH_LOG_ENTRY_LEN] {
-        let mut out = [0u8; AUTH_LOG_ENTRY_LEN];
-        if let Some(entry) = self.get(index) {
-            // Flags: bit0 = valid, bit1 = success
-            out[0] = 0x01 | if entry.success { 0x02 } else { 0x00 };
-            out[1..34].copy_from_slice(&entry.pubkey);
-            out[34..42].copy_from_slice(&entry.uptime_ms.to_le_bytes());
-            out[42..48].copy_from_slice(&entry.addr);
-            out[48..50].copy_from_slice(&entry.auth_action.to_le_bytes());
-        }
-        out
-    }
-}
 // GATT Server definition
 #[gatt_server]
 struct Server {
@@ -138,6 +69,21 @@ struct GateService {
     #[characteristic(uuid = "0108", read, value = 0)]
     perm: u8,
 
+    #[characteristic(uuid = "0109", read, value = [0; 6])]
+    device_id: [u8; 6],
+
+    #[characteristic(uuid = "010a", read, value = AUTH_VERSION)]
+    auth_version: u8,
+
+    #[characteristic(uuid = "010b", read, notify, value = 0)]
+    auth_result: u8,
+
+    #[characteristic(uuid = "0200", read, notify, value = [0; GATE_STATE_LEN])]
+    gate_state: [u8; GATE_STATE_LEN],
+
+    #[characteristic(uuid = "0201", read, value = [0; 2])]
+    input_levels: [u8; 2],
+
     #[characteristic(uuid = "1100", write, value=0)]
     management: u8,
 
@@ -156,16 +102,50 @@ struct GateService {
     #[characteristic(uuid = "1105", read, notify, value = 0)]
     management_result: u8,
 
+    #[characteristic(uuid = "1106", read, write, value = [0; 8])]
+    management_validity: [u8; 8],
+
+    #[characteristic(uuid = "1107", read, write, value = [0; schedule::SCHEDULE_LEN])]
+    management_schedule: [u8; schedule::SCHEDULE_LEN],
+
+    #[characteristic(uuid = "1108", read, write, value = [0; KEY_INFO_LEN])]
+    management_key_info: [u8; KEY_INFO_LEN],
+
     #[characteristic(uuid = "1200", read, write, value = 0)]
     log_index: u16,
 
-    #[characteristic(uuid = "1201", read, value = [0; AUTH_LOG_ENTRY_LEN])]
-    log_entry: [u8; AUTH_LOG_ENTRY_LEN],
+    #[characteristic(uuid = "1201", read, value = [0; authlog::ENTRY_LEN])]
+    log_entry: [u8; authlog::ENTRY_LEN],
 
     #[characteristic(uuid = "1202", read, value = 0)]
     log_count: u16,
+
+    #[characteristic(uuid = "1300", read, write, value = [0; clock::CTS_LEN])]
+    current_time: [u8; clock::CTS_LEN],
+
+    #[characteristic(uuid = "1210", read, value = [0; FAULT_HISTORY_LEN])]
+    fault_history: [u8; FAULT_HISTORY_LEN],
 }
 
+/// Size of the encoded gate status (see `gate_status_bytes`)
+const GATE_STATE_LEN: usize = 13;
+
+/// Fault records returned by the `fault_history` characteristic, newest first
+const FAULT_HISTORY_RECORDS: usize = 8;
+/// Record count (u8) followed by `FAULT_HISTORY_RECORDS` records
+const FAULT_HISTORY_LEN: usize = 1 + FAULT_HISTORY_RECORDS * faultlog::RECORD_LEN;
+
+/// Interval of `gate_state` notifications while a door moves (position updates)
+const POSITION_NOTIFY_MS: u64 = 500;
+
+/// Fixed device address, also used as the device identity in the auth challenge
+const DEVICE_ADDRESS: [u8; 6] = [0xff, 0x8f, 0x1a, 0x05, 0xe4, 0xff];
+
+/// Current challenge format version (see `auth_digest_v2`)
+const AUTH_VERSION: u8 = 2;
+/// Domain separator prefixed to the v2 challenge
+const AUTH_DOMAIN_V2: &[u8] = b"BlueGate-auth-v2";
+
 /// Admin permission flag (MSB high means admin)
 const PERM_ADMIN: u8 = 0x80;
 const PERM_ADMADMIN: u8 = 0x40;
@@ -175,9 +155,21 @@ const PERM_SETADMIN: u8 = 0x20;
 const MGMT_ADD_KEY: u8 = 0x01;
 const MGMT_DEL_KEY: u8 = 0x02;
 const MGMT_GET_KEY: u8 = 0x03;
+const MGMT_SET_VALIDITY: u8 = 0x04;
+const MGMT_GET_VALIDITY: u8 = 0x05;
+const MGMT_SET_KEY_SCHEDULE: u8 = 0x06;
+const MGMT_GET_KEY_SCHEDULE: u8 = 0x07;
+const MGMT_SET_USES: u8 = 0x08;
+const MGMT_GET_USES: u8 = 0x09;
+const MGMT_SET_LABEL: u8 = 0x0A;
+const MGMT_GET_KEY_INFO: u8 = 0x0B;
 const MGMT_SET_PARAM: u8 = 0x10;
 const MGMT_GET_PARAM: u8 = 0x11;
 const MGMT_SET_NAME: u8 = 0x20;
+const MGMT_CLEAR_FAULT: u8 = 0x30;
+const MGMT_CLEAR_FAULT_LOG: u8 = 0x31;
+const MGMT_SET_SCHEDULE: u8 = 0x40;
+const MGMT_GET_SCHEDULE: u8 = 0x41;
 
 /// Management result codes
 const MGMT_OK: u8 = 0x00;
@@ -186,23 +178,35 @@ const MGMT_ERR_FLASH: u8 = 0x02;
 const MGMT_ERR_NOT_FOUND: u8 = 0x03;
 const MGMT_ERR_INVALID: u8 = 0x04;
 
+/// Authentication result codes
+const AUTH_OK: u8 = 0x00;
+const AUTH_ERR_SIGNATURE: u8 = 0x01;
+const AUTH_ERR_LOCKED_OUT: u8 = 0x02;
+const AUTH_ERR_UNKNOWN_KEY: u8 = 0x03;
+const AUTH_ERR_INVALID: u8 = 0x04;
+const AUTH_ERR_NOT_VALID: u8 = 0x05;
+const AUTH_ERR_OUTSIDE_SCHEDULE: u8 = 0x06;
+const AUTH_ERR_TIME_UNSYNCED: u8 = 0x07;
+const AUTH_ERR_USED_UP: u8 = 0x08;
+
 // Run the BLE stack.
 pub async fn run<C, RNG, S>(
     controller: C,
     rng: &mut RNG,
     name: &String<MAX_NAME_LEN>,
     mut keys: KeyStore,
+    mut schedules: ScheduleStore,
     mut config: ConfigStore<S>,
     tx: Sender<'_, CriticalSectionRawMutex, FsmCommand, 4>,
     cfg_prog_mode: bool,
 ) where
     C: Controller,
     RNG: RngCore + CryptoRng,
-    S: NorFlash,
+    S: MultiwriteNorFlash,
 {
     // Using a fixed "random" address can be useful for testing. In real scenarios, one would
     // use e.g. the MAC 6 byte array as the address (how to get that varies by the platform).
-    let address: Address = Address::random([0xff, 0x8f, 0x1a, 0x05, 0xe4, 0xff]);
+    let address: Address = Address::random(DEVICE_ADDRESS);
     println!("Our address = {:?}", address);
 
     let mut resources: HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX> =
@@ -222,17 +226,24 @@ pub async fn run<C, RNG, S>(
     }))
     .unwrap();
 
-    let mut auth_log = AuthLog::new();
+    let log_capacity = config.get(ConfigSlot::LogCapacity, authlog::DEFAULT_CAPACITY as u32).await;
+    let mut auth_log = AuthLog::new(config.flash(), log_capacity as usize).await;
+    let mut fault_log = FaultLog::new(config.flash()).await;
+    let mut limiter = AuthLimiter::new();
 
     // let rng: SeedableRng = SeedableRng::seed_from_u64(1234);
     let _ = join(ble_task(runner), async {
         loop {
-            match advertise(name, &mut peripheral, &server).await {
-                Ok(conn) => {
+            record_faults(&mut fault_log, &mut config).await;
+            // Stop advertising to record a fault raised meanwhile, then start again
+            match select(advertise(name, &mut peripheral, &server), FAULT_EVENTS.ready_to_receive()).await {
+                Either::First(Ok(conn)) => {
                     server.gate.client_key_ack.set(&server, &false).unwrap();
                     println!("Set authenticate_ack {}",false);
                     server.gate.authenticate_ack.set(&server, &false).unwrap();
+                    server.gate.auth_result.set(&server, &AUTH_OK).unwrap();
                     server.gate.auth_action.set(&server, &1u16).unwrap(); // Default: open door
+                    server.gate.device_id.set(&server, &DEVICE_ADDRESS).unwrap();
                     server.gate.management.set(&server, &0).unwrap();
                     // Populate management_name with current device name
                     let current_name = config.get_name("BlueGate").await;
@@ -249,29 +260,61 @@ pub async fn run<C, RNG, S>(
                         &server,
                         &conn,
                         &mut keys,
+                        &mut schedules,
                         &mut config,
                         &mut auth_log,
+                        &mut fault_log,
+                        &mut limiter,
+                        rng,
                         tx,
                         cfg_prog_mode,
                     );
                     // let b = custom_task(&conn, &stack);
+                    let b = gate_state_task(&server, &conn);
                     let c = connection_timeout_task(&server, timeout);
                     // run until any task ends (usually because the connection has been closed),
                     // then return to advertising state.
-                    select(a, c).await;
+                    select3(a, b, c).await;
                     // select(select(a, b), c).await;
                 }
-                Err(e) => {
+                Either::First(Err(e)) => {
                     //#[cfg(feature = "defmt")]
                     //let e = defmt::Debug2Format(&e);
                     panic!("[adv] error: {:?}", e);
                 }
+                Either::Second(_) => {
+                    // Recorded at the top of the loop
+                }
             }
         }
     })
     .await;
 }
 
+/// Write the faults queued by the FSM to the fault history
+async fn record_faults<S: MultiwriteNorFlash>(fault_log: &mut FaultLog, config: &mut ConfigStore<S>) {
+    while let Ok(event) = FAULT_EVENTS.try_receive() {
+        if let Err(e) = fault_log.push(config.flash(), FaultRecord::from_event(event)).await {
+            println!("ERROR: fault log write failed: {:?}", e);
+        }
+    }
+}
+
+/// Encode the newest fault records for the `fault_history` characteristic
+async fn fault_history_bytes<S: NorFlash>(fault_log: &mut FaultLog, config: &mut ConfigStore<S>) -> [u8; FAULT_HISTORY_LEN] {
+    let mut records = [None; FAULT_HISTORY_RECORDS];
+    let count = fault_log.read_newest(config.flash(), &mut records).await;
+    let mut out = [0u8; FAULT_HISTORY_LEN];
+    out[0] = count.min(u8::MAX as usize) as u8;
+    for (i, record) in records.iter().enumerate() {
+        if let Some(record) = record {
+            let at = 1 + i * faultlog::RECORD_LEN;
+            out[at..at + faultlog::RECORD_LEN].copy_from_slice(&record.to_bytes());
+        }
+    }
+    out
+}
+
 /// This is a background task that is required to run forever alongside any other BLE tasks.
 ///
 /// ## Alternative
@@ -301,16 +344,28 @@ async fn ble_task<C: Controller, P: PacketPool>(mut runner: Runner<'_, C, P>) {
 ///
 /// This function will handle the GATT events and process them.
 /// This is how we interact with read and write requests.
-async fn gatt_events_task<P: PacketPool, S: NorFlash>(
+async fn gatt_events_task<P: PacketPool, S: MultiwriteNorFlash, RNG: RngCore + CryptoRng>(
     server: &Server<'_>,
     conn: &GattConnection<'_, '_, P>,
     keys: &mut KeyStore,
+    schedules: &mut ScheduleStore,
     config: &mut ConfigStore<S>,
     auth_log: &mut AuthLog,
+    fault_log: &mut FaultLog,
+    limiter: &mut AuthLimiter,
+    rng: &mut RNG,
     tx: Sender<'_, CriticalSectionRawMutex, FsmCommand, 4>,
     prog_mode: bool,
 ) -> Result<(), Error> {
     // let level = server.battery_service.level;
+    let auth_legacy = config.get(ConfigSlot::AuthLegacy, 0).await != 0;
+    let mut addr_bytes = [0u8; 6];
+    addr_bytes.copy_from_slice(conn.raw().peer_address().raw());
+    // Key written to `client_pubkey` in the stored format, and why it was not accepted
+    let mut client_key = [0u8; 33];
+    let mut key_denied = AUTH_ERR_UNKNOWN_KEY;
+    // A stored key refused on its `client_pubkey` write is logged right away, not again on `authenticate`
+    let mut denial_logged = false;
     let get_name = |handle| {
     if      handle == server.gate.nonce.handle { "nonce" }
     else if handle == server.gate.authenticate.handle { "authenticate" }
@@ -319,6 +374,9 @@ async fn gatt_events_task<P: PacketPool, S: NorFlash>(
     else if handle == server.gate.client_key_ack.handle { "client_key_ack" }
     else if handle == server.gate.authenticate_ack.handle { "authenticate_ack" }
     else if handle == server.gate.perm.handle { "perm" }
+    else if handle == server.gate.device_id.handle { "device_id" }
+    else if handle == server.gate.auth_version.handle { "auth_version" }
+    else if handle == server.gate.auth_result.handle { "auth_result" }
     else if handle == server.gate.auth_action.handle { "auth_action" }
     else if handle == server.gate.management.handle { "management" }
     else if handle == server.gate.management_key.handle { "management_key" }
@@ -326,11 +384,27 @@ async fn gatt_events_task<P: PacketPool, S: NorFlash>(
     else if handle == server.gate.management_param_val.handle { "management_param_val" }
     else if handle == server.gate.management_name.handle { "management_name" }
     else if handle == server.gate.management_result.handle { "management_result" }
+    else if handle == server.gate.management_validity.handle { "management_validity" }
+    else if handle == server.gate.management_schedule.handle { "management_schedule" }
+    else if handle == server.gate.management_key_info.handle { "management_key_info" }
     else if handle == server.gate.log_index.handle { "log_index" }
+    else if handle == server.gate.current_time.handle { "current_time" }
+    else if handle == server.gate.gate_state.handle { "gate_state" }
+    else if handle == server.gate.input_levels.handle { "input_levels" }
+    else if handle == server.gate.fault_history.handle { "fault_history" }
     else {"unknown"}
     };
     let reason = loop {
-        match conn.next().await {
+        // Faults are recorded as they come: an admin connection never times out, and
+        // `FAULT_EVENTS` only holds a few
+        let next = match select(conn.next(), FAULT_EVENTS.ready_to_receive()).await {
+            Either::First(next) => next,
+            Either::Second(_) => {
+                record_faults(fault_log, config).await;
+                continue;
+            }
+        };
+        match next {
             GattConnectionEvent::Disconnected { reason } => break reason,
             GattConnectionEvent::Gatt { event } => {
                 match &event {
@@ -342,9 +416,30 @@ async fn gatt_events_task<P: PacketPool, S: NorFlash>(
                         }
                         if event.handle() == server.gate.log_entry.handle {
                             let index = server.gate.log_index.get(server).unwrap_or(0) as usize;
-                            let entry = auth_log.entry_bytes(index);
+                            let entry = auth_log.entry_bytes(config.flash(), index).await;
                             server.gate.log_entry.set(server, &entry).unwrap();
                         }
+                        if event.handle() == server.gate.gate_state.handle {
+                            // Refresh so the remaining autoclose time is current
+                            if let Some(status) = GATE_STATUS.try_get() {
+                                let value = gate_status_bytes(&status, Instant::now());
+                                server.gate.gate_state.set(server, &value).unwrap();
+                            }
+                        }
+                        if event.handle() == server.gate.input_levels.handle {
+                            let levels = gpi::input_levels();
+                            server.gate.input_levels.set(server, &[levels.active, levels.fitted]).unwrap();
+                        }
+                        if event.handle() == server.gate.fault_history.handle {
+                            // A fault raised together with this read may not be recorded yet
+                            record_faults(fault_log, config).await;
+                            let history = fault_history_bytes(fault_log, config).await;
+                            server.gate.fault_history.set(server, &history).unwrap();
+                        }
+                        if event.handle() == server.gate.current_time.handle {
+                            let now = DateTime::now().map(|t| t.to_cts_bytes()).unwrap_or([0; clock::CTS_LEN]);
+                            server.gate.current_time.set(server, &now).unwrap();
+                        }
                         // if event.handle() == level.handle {
                         //     let value = server.get(&level);
                         //     println!("[gatt] Read Event to Level Characteristic: {:?}", value);
@@ -361,49 +456,91 @@ async fn gatt_events_task<P: PacketPool, S: NorFlash>(
                             //     HexFmt(event.data())
                             // );
                             let mut auth_success = false;
+                            // The action is part of the signed digest, so read it before verifying
+                            // and act on exactly this value afterwards.
+                            let auth_action = server.gate.auth_action.get(server).unwrap_or(0);
+                            let key_known = server.gate.client_key_ack.get(server).unwrap_or(false);
                             let d = event.data();
-                            if d.len() == 64 {
-                                let mut hasher = Sha256::new();
-                                hasher.update(&server.gate.nonce.get(&server).unwrap());
-                                hasher.update(&server.gate.client_nonce.get(&server).unwrap());
-                                let digest: [u8; 32] = hasher.finalize().into();
-                                // println!("Digest {:?}", HexFmt(digest));
-                                // println!("Signature {:?}", HexFmt(d));
+                            let now = Instant::now();
+                            // Rejections during a lockout are merged into one auth log entry
+                            let mut log_attempt = true;
+                            let auth_result = if let Some(remaining) = limiter.check(&addr_bytes, now) {
+                                println!("Peer locked out for {} ms", remaining.as_millis());
+                                log_attempt = limiter.log_rejection(now, remaining);
+                                AUTH_ERR_LOCKED_OUT
+                            } else if d.len() != 64 {
+                                AUTH_ERR_INVALID
+                            } else if !key_known {
+                                log_attempt = !denial_logged;
+                                key_denied
+                            } else {
+                                let server_nonce = server.gate.nonce.get(server)?;
+                                let client_nonce = server.gate.client_nonce.get(server)?;
                                 let pubkey = server.gate.client_pubkey.get(server)?;
                                 println!("PubKey {:?}", HexFmt(pubkey));
 
-                                let keytype = pubkey[0];
-                                if keytype == 1 {
-                                    let key32: &[u8; 32] = pubkey[1..33].try_into().unwrap();
-                                    let verifying_key = VerifyingKey::from_bytes(&key32)
-                                        .map_err(|_| Error::InvalidValue)?;
-                                        auth_success = verifying_key
-                                        .verify(
-                                            &digest,
-                                            &ed25519::Signature::from_slice(&d[..]).unwrap(),
-                                        )
-                                        .is_ok();
-                                } else if keytype == 2 || keytype == 3 {
-                                    auth_success = verify_secp256r1_sha256(&digest, d, &pubkey)
+                                let digest = auth_digest_v2(&server_nonce, &client_nonce, auth_action, &DEVICE_ADDRESS, &pubkey);
+                                auth_success = verify_signature(&digest, d, &pubkey);
+                                if !auth_success && auth_legacy {
+                                    let digest = auth_digest_v1(&server_nonce, &client_nonce);
+                                    auth_success = verify_signature(&digest, d, &pubkey);
+                                    if auth_success {
+                                        println!("Accepted legacy v1 challenge");
+                                    }
                                 }
+                                if auth_success { AUTH_OK } else { AUTH_ERR_SIGNATURE }
+                            };
+                            match auth_result {
+                                AUTH_OK => limiter.record_success(&addr_bytes),
+                                AUTH_ERR_SIGNATURE => limiter.record_failure(&addr_bytes, now),
+                                _ => {}
                             }
-                            let auth_action = server.gate.auth_action.get(server).unwrap_or(0);
+                            // Server nonce is single use: every attempt needs a fresh challenge
+                            let mut nonce = [0u8; 32];
+                            rng.fill_bytes(&mut nonce);
+                            server.gate.nonce.set(server, &nonce).unwrap();
+
                             let perm = server.gate.perm.get(server).unwrap_or(0);
-                            println!("Auth {} perm {} action {}", auth_success, perm, auth_action);
+                            println!("Auth {} result {} perm {} action {}", auth_success, auth_result, perm, auth_action);
+                            server.gate.auth_result.set(server, &auth_result).unwrap();
                             server.gate.authenticate_ack.set(server, &auth_success).unwrap();
                             let pubkey = server.gate.client_pubkey.get(server).unwrap_or([0u8; 33]);
-                            let mut addr_bytes = [0u8; 6];
-                            addr_bytes.copy_from_slice(conn.raw().peer_address().raw());
-                            auth_log.push(AuthLogEntry {
+                            let entry = AuthLogEntry {
                                 pubkey,
-                                uptime_ms: Instant::now().as_millis(),
+                                uptime_ms: now.as_millis(),
+                                time: DateTime::now(),
                                 addr: addr_bytes,
                                 success: auth_success,
                                 auth_action,
-                            });
+                                result: Some(auth_result),
+                            };
+                            if log_attempt {
+                                if let Err(e) = auth_log.push(config.flash(), entry).await {
+                                    println!("ERROR: auth log write failed: {:?}", e);
+                                }
+                            }
 
-                            if auth_success {
-                                let action_code = auth_action & 0x7f;
+                            let action_code = auth_action & 0x7f;
+                            // Opening uses up one use of a limited key, a use that cannot be
+                            // recorded does not open
+                            let mut use_recorded = true;
+                            if auth_success && matches!(action_code, 1 | 2 | 5) && !prog_mode {
+                                match keys.record_use(config.flash(), client_key).await {
+                                    Ok(Some(0)) => {
+                                        println!("Key used up");
+                                        server.gate.client_key_ack.set(server, &false).unwrap();
+                                        key_denied = AUTH_ERR_USED_UP;
+                                    }
+                                    Ok(Some(left)) => println!("Key has {} uses left", left),
+                                    Ok(None) => {}
+                                    Err(e) => {
+                                        println!("ERROR: key use write failed: {:?}", e);
+                                        use_recorded = false;
+                                    }
+                                }
+                            }
+
+                            if auth_success && use_recorded {
                                 match action_code {
                                     1 => {
                                         let r = tx.send(FsmCommand::Open).await;
@@ -421,6 +558,14 @@ async fn gatt_events_task<P: PacketPool, S: NorFlash>(
                                         let r = tx.send(FsmCommand::Close).await;
                                         println!("Authenticated, closing door {:?}", r);
                                     }
+                                    4 => {
+                                        let r = tx.send(FsmCommand::Stop).await;
+                                        println!("Authenticated, stopping door {:?}", r);
+                                    }
+                                    5 => {
+                                        let r = tx.send(FsmCommand::OpenPedestrian).await;
+                                        println!("Authenticated, opening pedestrian door {:?}", r);
+                                    }
                                     _ => {}
                                 }
                             }
@@ -431,30 +576,98 @@ async fn gatt_events_task<P: PacketPool, S: NorFlash>(
                                 event.data().len(),
                                 HexFmt(event.data()),
                             );
+                            // The session belongs to the key that signed it; a new key has to
+                            // authenticate again, or a guest session could take an admin's rights
+                            if server.gate.authenticate_ack.get(server).unwrap_or(false) {
+                                println!("Key changed after authentication, revoking session");
+                                server.gate.authenticate_ack.set(server, &false).unwrap();
+                            }
                             let d = event.data();
                             let mut lookup_key = [0u8; 33];
-                            let perm: u8;
+                            let access: Result<u8, AccessDenied>;
                             if prog_mode {
-                                perm=128;
+                                access = Ok(128);
                             }else{
                                 if d.len() == 32 {
                                     // ed25519: flag byte 0x01, then 32 bytes of key
                                     lookup_key[0] = 0x01;
                                     lookup_key[1..].copy_from_slice(d);
-                                    perm = keys.lookup(&lookup_key);
+                                    access = keys.authorize(&lookup_key, clock::now_unix(), schedules);
                                 } else if d.len() == 33 {
                                     // secp256r1: first byte has flags, then 32 bytes
                                     lookup_key.copy_from_slice(d);
-                                    perm = keys.lookup(&lookup_key);
+                                    access = keys.authorize(&lookup_key, clock::now_unix(), schedules);
                                 } else {
-                                    perm = 0;
+                                    access = Err(AccessDenied::UnknownKey);
                                 }
                             }
+                            client_key = lookup_key;
+                            denial_logged = false;
+                            let perm = match access {
+                                Ok(perm) => perm,
+                                Err(reason) => {
+                                    println!("Key not accepted: {:?}", reason);
+                                    key_denied = match reason {
+                                        AccessDenied::UnknownKey => AUTH_ERR_UNKNOWN_KEY,
+                                        AccessDenied::NotValid => AUTH_ERR_NOT_VALID,
+                                        AccessDenied::OutsideSchedule => AUTH_ERR_OUTSIDE_SCHEDULE,
+                                        AccessDenied::TimeUnsynced => AUTH_ERR_TIME_UNSYNCED,
+                                        AccessDenied::UsedUp => AUTH_ERR_USED_UP,
+                                    };
+                                    // A stored key that is refused is logged even if the app stops here
+                                    if reason != AccessDenied::UnknownKey {
+                                        server.gate.auth_result.set(server, &key_denied).unwrap();
+                                        let entry = AuthLogEntry {
+                                            pubkey: server.gate.client_pubkey.get(server).unwrap_or([0u8; 33]),
+                                            uptime_ms: Instant::now().as_millis(),
+                                            time: DateTime::now(),
+                                            addr: addr_bytes,
+                                            success: false,
+                                            auth_action: server.gate.auth_action.get(server).unwrap_or(0),
+                                            result: Some(key_denied),
+                                        };
+                                        if let Err(e) = auth_log.push(config.flash(), entry).await {
+                                            println!("ERROR: auth log write failed: {:?}", e);
+                                        }
+                                        denial_logged = true;
+                                    }
+                                    0
+                                }
+                            };
                             let value = perm > 0;
                             println!("matched = {} perm {}", value, perm);
                             server.gate.client_key_ack.set(server, &value).unwrap();
                             server.gate.perm.set(server, &(perm & 0xfc)).unwrap();
                         }
+                        if event.handle() == server.gate.auth_action.handle {
+                            // The action was bound into the signature; changing it afterwards
+                            // ends the authenticated session.
+                            if server.gate.authenticate_ack.get(server).unwrap_or(false) {
+                                println!("Action changed after authentication, revoking session");
+                                server.gate.authenticate_ack.set(server, &false).unwrap();
+                            }
+                        }
+                        if event.handle() == server.gate.current_time.handle {
+                            // Setting the clock needs an authenticated admin session, or any
+                            // authenticated key if TimeSyncAnyKey is set
+                            let auth = server.gate.authenticate_ack.get(server).unwrap_or(false);
+                            let perm = server.gate.perm.get(server).unwrap_or(0);
+                            let any_key = config.get(ConfigSlot::TimeSyncAnyKey, 0).await != 0;
+                            let result = if !auth || (!any_key && (perm & PERM_ADMIN) != PERM_ADMIN) {
+                                MGMT_ERR_NOT_ADMIN
+                            } else {
+                                match DateTime::from_cts_bytes(event.data()).and_then(|t| t.to_unix()) {
+                                    Some(unix) => {
+                                        let was_synced = clock::is_synced();
+                                        clock::set_unix_ms(unix * 1000);
+                                        println!("Clock set to {} (was synced: {})", DateTime::from_unix(unix), was_synced);
+                                        MGMT_OK
+                                    }
+                                    None => MGMT_ERR_INVALID,
+                                }
+                            };
+                            server.gate.management_result.set(server, &result).unwrap();
+                        }
                         if event.handle() == server.gate.log_index.handle {
                             if event.data().len() == 2 {
                                 let index = u16::from_le_bytes([event.data()[0], event.data()[1]]);
@@ -481,7 +694,9 @@ async fn gatt_events_task<P: PacketPool, S: NorFlash>(
                                         let key = server.gate.management_key.get(server).unwrap_or([0; 33]);
                                         println!("Adding key: {}", HexFmt(&key));
                                         if (key[0] & 0xf0 == 0 ) | is_admadmin {
-                                            match keys.add(config.flash(), key).await {
+                                            let creator = (!prog_mode).then_some(&client_key);
+                                            let info = KeyInfo::new(clock::now_unix(), creator);
+                                            match keys.add(config.flash(), key, info).await {
                                                 Ok(true) => {
                                                     println!("Key added successfully");
                                                     MGMT_OK
@@ -525,6 +740,141 @@ async fn gatt_events_task<P: PacketPool, S: NorFlash>(
                                             MGMT_ERR_NOT_ADMIN
                                         }
                                     }
+                                    MGMT_SET_VALIDITY => {
+                                        let key = server.gate.management_key.get(server).unwrap_or([0; 33]);
+                                        let bytes = server.gate.management_validity.get(server).unwrap_or([0; 8]);
+                                        let validity = Validity::from_bytes(&bytes);
+                                        println!("Setting validity of key {}: {:?}", HexFmt(&key), validity);
+                                        let found = keys.lookup(&key);
+                                        if found==0 {
+                                            println!("Key not found");
+                                            MGMT_ERR_NOT_FOUND
+                                        }else if validity.not_before != 0 && validity.not_after != 0 && validity.not_after < validity.not_before {
+                                            MGMT_ERR_INVALID
+                                        }else if found & 0xf0 == 0 || is_admadmin {
+                                            match keys.set_validity(config.flash(), key, validity).await {
+                                                Ok(true) => MGMT_OK,
+                                                Ok(false) => MGMT_ERR_NOT_FOUND,
+                                                Err(_) => {
+                                                    println!("Flash error storing key validity");
+                                                    MGMT_ERR_FLASH
+                                                }
+                                            }
+                                        }else{
+                                            MGMT_ERR_NOT_ADMIN
+                                        }
+                                    }
+                                    MGMT_GET_VALIDITY => {
+                                        let key = server.gate.management_key.get(server).unwrap_or([0; 33]);
+                                        match keys.validity(&key) {
+                                            Some(validity) => {
+                                                server.gate.management_validity.set(server, &validity.to_bytes()).unwrap();
+                                                MGMT_OK
+                                            }
+                                            None => MGMT_ERR_NOT_FOUND,
+                                        }
+                                    }
+                                    MGMT_SET_KEY_SCHEDULE => {
+                                        let key = server.gate.management_key.get(server).unwrap_or([0; 33]);
+                                        let value = u32::from_le_bytes(server.gate.management_param_val.get(server).unwrap_or([0; 4]));
+                                        println!("Setting schedule of key {}: {}", HexFmt(&key), value);
+                                        let found = keys.lookup(&key);
+                                        if found==0 {
+                                            println!("Key not found");
+                                            MGMT_ERR_NOT_FOUND
+                                        }else if value != NO_SCHEDULE as u32 && (value > u8::MAX as u32 || schedules.get(value as u8).is_none()) {
+                                            // Only defined profiles can be assigned
+                                            MGMT_ERR_INVALID
+                                        }else if found & 0xf0 == 0 || is_admadmin {
+                                            match keys.set_schedule(config.flash(), key, value as u8).await {
+                                                Ok(true) => MGMT_OK,
+                                                Ok(false) => MGMT_ERR_NOT_FOUND,
+                                                Err(_) => {
+                                                    println!("Flash error storing key schedule");
+                                                    MGMT_ERR_FLASH
+                                                }
+                                            }
+                                        }else{
+                                            MGMT_ERR_NOT_ADMIN
+                                        }
+                                    }
+                                    MGMT_GET_KEY_SCHEDULE => {
+                                        let key = server.gate.management_key.get(server).unwrap_or([0; 33]);
+                                        match keys.schedule(&key) {
+                                            Some(id) => {
+                                                server.gate.management_param_val.set(server, &(id as u32).to_le_bytes()).unwrap();
+                                                MGMT_OK
+                                            }
+                                            None => MGMT_ERR_NOT_FOUND,
+                                        }
+                                    }
+                                    MGMT_SET_USES => {
+                                        let key = server.gate.management_key.get(server).unwrap_or([0; 33]);
+                                        let uses = u32::from_le_bytes(server.gate.management_param_val.get(server).unwrap_or([0; 4]));
+                                        println!("Setting uses of key {}: {}", HexFmt(&key), uses);
+                                        let found = keys.lookup(&key);
+                                        if found==0 {
+                                            println!("Key not found");
+                                            MGMT_ERR_NOT_FOUND
+                                        }else if found & 0xf0 == 0 || is_admadmin {
+                                            match keys.set_uses(config.flash(), key, uses).await {
+                                                Ok(true) => MGMT_OK,
+                                                Ok(false) => MGMT_ERR_NOT_FOUND,
+                                                Err(_) => {
+                                                    println!("Flash error storing key uses");
+                                                    MGMT_ERR_FLASH
+                                                }
+                                            }
+                                        }else{
+                                            MGMT_ERR_NOT_ADMIN
+                                        }
+                                    }
+                                    MGMT_GET_USES => {
+                                        let key = server.gate.management_key.get(server).unwrap_or([0; 33]);
+                                        match keys.uses(&key) {
+                                            Some(uses) => {
+                                                server.gate.management_param_val.set(server, &uses.to_le_bytes()).unwrap();
+                                                MGMT_OK
+                                            }
+                                            None => MGMT_ERR_NOT_FOUND,
+                                        }
+                                    }
+                                    MGMT_SET_LABEL => {
+                                        let key = server.gate.management_key.get(server).unwrap_or([0; 33]);
+                                        let bytes = server.gate.management_key_info.get(server).unwrap_or([0; KEY_INFO_LEN]);
+                                        let mut label = [0u8; LABEL_LEN];
+                                        label.copy_from_slice(&bytes[..LABEL_LEN]);
+                                        println!("Setting label of key {}: {}", HexFmt(&key), HexFmt(&label));
+                                        let found = keys.lookup(&key);
+                                        if found==0 {
+                                            println!("Key not found");
+                                            MGMT_ERR_NOT_FOUND
+                                        }else if (KeyInfo { label, ..KeyInfo::default() }).label().is_none() {
+                                            MGMT_ERR_INVALID
+                                        }else if found & 0xf0 == 0 || is_admadmin {
+                                            match keys.set_label(config.flash(), key, label).await {
+                                                Ok(true) => MGMT_OK,
+                                                Ok(false) => MGMT_ERR_NOT_FOUND,
+                                                Err(_) => {
+                                                    println!("Flash error storing key label");
+                                                    MGMT_ERR_FLASH
+                                                }
+                                            }
+                                        }else{
+                                            MGMT_ERR_NOT_ADMIN
+                                        }
+                                    }
+                                    MGMT_GET_KEY_INFO => {
+                                        let key = server.gate.management_key.get(server).unwrap_or([0; 33]);
+                                        match keys.info(config.flash(), &key).await {
+                                            Ok(Some(info)) => {
+                                                server.gate.management_key_info.set(server, &info.to_bytes()).unwrap();
+                                                MGMT_OK
+                                            }
+                                            Ok(None) => MGMT_ERR_NOT_FOUND,
+                                            Err(_) => MGMT_ERR_FLASH,
+                                        }
+                                    }
                                     MGMT_GET_KEY => {
                                         let index_bytes = server.gate.management_param_val.get(server).unwrap_or([0; 4]);
                                         let index = u32::from_le_bytes(index_bytes) as usize;
@@ -536,6 +886,10 @@ async fn gatt_events_task<P: PacketPool, S: NorFlash>(
                                             Some(key) => {
                                                 println!("Key found: {}", HexFmt(key));
                                                 server.gate.management_key.set(server, key).unwrap();
+                                                let validity = keys.get_validity(index).unwrap_or_default();
+                                                server.gate.management_validity.set(server, &validity.to_bytes()).unwrap();
+                                                let info = keys.info(config.flash(), key).await.ok().flatten().unwrap_or_default();
+                                                server.gate.management_key_info.set(server, &info.to_bytes()).unwrap();
                                                 MGMT_OK
                                             }
                                             None => {
@@ -544,22 +898,62 @@ async fn gatt_events_task<P: PacketPool, S: NorFlash>(
                                             }
                                         }
                                     }
+                                    MGMT_SET_SCHEDULE => {
+                                        let id = server.gate.management_param_id.get(server).unwrap_or(0);
+                                        let bytes = server.gate.management_schedule.get(server).unwrap_or([0; schedule::SCHEDULE_LEN]);
+                                        println!("Setting schedule {}: {}", id, HexFmt(&bytes));
+                                        if !is_admadmin {
+                                            MGMT_ERR_NOT_ADMIN
+                                        } else {
+                                            match Schedule::from_bytes(&bytes) {
+                                                Some(profile) if ScheduleStore::is_valid_id(id) => {
+                                                    match schedules.set(config.flash(), id, profile).await {
+                                                        Ok(_) => MGMT_OK,
+                                                        Err(_) => {
+                                                            println!("Flash error storing schedule");
+                                                            MGMT_ERR_FLASH
+                                                        }
+                                                    }
+                                                }
+                                                _ => MGMT_ERR_INVALID,
+                                            }
+                                        }
+                                    }
+                                    MGMT_GET_SCHEDULE => {
+                                        let id = server.gate.management_param_id.get(server).unwrap_or(0);
+                                        match schedules.get(id) {
+                                            Some(profile) => {
+                                                server.gate.management_schedule.set(server, &profile.to_bytes()).unwrap();
+                                                MGMT_OK
+                                            }
+                                            None => MGMT_ERR_NOT_FOUND,
+                                        }
+                                    }
                                     MGMT_SET_PARAM => {
                                         if is_setadmin {
                                             let slot = server.gate.management_param_id.get(server).unwrap_or(0);
                                             let value = u32::from_le_bytes(server.gate.management_param_val.get(server).unwrap_or([0,0,0,0]));
                                             println!("Setting param slot {} = {}", slot, value);
-                                            if slot==31 {
+                                            if slot == ConfigSlot::Reset.as_u8() {
                                                 esp_hal::system::software_reset();
                                             }
-                                            match config.set_slot(slot, value).await {
-                                                Ok(()) => {
-                                                    println!("Param set successfully");
-                                                    MGMT_OK
-                                                }
-                                                Err(_) => {
-                                                    println!("Flash error setting param");
-                                                    MGMT_ERR_FLASH
+                                            let utc_offset = schedule::utc_offset_from_config(value);
+                                            if slot == ConfigSlot::UtcOffset.as_u8() && utc_offset.is_none() {
+                                                MGMT_ERR_INVALID
+                                            } else {
+                                                match config.set_slot(slot, value).await {
+                                                    Ok(()) => {
+                                                        println!("Param set successfully");
+                                                        // Applies at once, so schedules follow a DST change without a reset
+                                                        if slot == ConfigSlot::UtcOffset.as_u8() {
+                                                            schedules.set_utc_offset(utc_offset.unwrap_or(0));
+                                                        }
+                                                        MGMT_OK
+                                                    }
+                                                    Err(_) => {
+                                                        println!("Flash error setting param");
+                                                        MGMT_ERR_FLASH
+                                                    }
                                                 }
                                             }
                                         } else {
@@ -590,6 +984,17 @@ async fn gatt_events_task<P: PacketPool, S: NorFlash>(
                                             }
                                         }
                                     }
+                                    MGMT_CLEAR_FAULT => {
+                                        let r = tx.send(FsmCommand::ClearFault).await;
+                                        println!("Clearing active fault {:?}", r);
+                                        MGMT_OK
+                                    }
+                                    MGMT_CLEAR_FAULT_LOG => {
+                                        record_faults(fault_log, config).await;
+                                        fault_log.clear(config.flash()).await;
+                                        println!("Fault history cleared");
+                                        MGMT_OK
+                                    }
                                     _ => {
                                         println!("Unknown management action");
                                         MGMT_ERR_INVALID
@@ -683,6 +1088,70 @@ async fn advertise<'values, 'server, C: Controller>(
     Ok(conn)
 }
 
+/// Publish gate status changes from the FSM to the `gate_state` characteristic.
+/// Never returns; it is dropped when the connection closes.
+async fn gate_state_task<P: PacketPool>(server: &Server<'_>, conn: &GattConnection<'_, '_, P>) {
+    let Some(mut rx) = GATE_STATUS.receiver() else {
+        println!("[gate_state] no free status receiver, notifications disabled");
+        return core::future::pending().await;
+    };
+    let mut status = rx.try_get().unwrap_or_default();
+    let value = gate_status_bytes(&status, Instant::now());
+    server.gate.gate_state.set(server, &value).unwrap();
+    loop {
+        let moving = status.left.movement != DoorMovement::Stopped || status.right.movement != DoorMovement::Stopped;
+        if moving {
+            // Re-notify periodically so the estimated position can be followed
+            if let Either::First(changed) = select(rx.changed(), Timer::after_millis(POSITION_NOTIFY_MS)).await {
+                status = changed;
+            }
+        } else {
+            status = rx.changed().await;
+        }
+        let value = gate_status_bytes(&status, Instant::now());
+        if let Err(e) = server.gate.gate_state.notify(conn, &value).await {
+            println!("[gate_state] notify failed: {:?}", e);
+        }
+    }
+}
+
+/// Encode the gate status for the `gate_state` characteristic:
+/// state, left movement, right movement, flags, remaining autoclose ms (u32 LE),
+/// left and right estimated position in per mille open (u16 LE), fault code (0 = none)
+fn gate_status_bytes(status: &GateStatus, now: Instant) -> [u8; GATE_STATE_LEN] {
+    let movement = |m: DoorMovement| match m {
+        DoorMovement::Stopped => 0,
+        DoorMovement::Opening => 1,
+        DoorMovement::Closing => 2,
+    };
+    let mut out = [0u8; GATE_STATE_LEN];
+    out[0] = match status.state {
+        GateState::Closed => 0,
+        GateState::Opening => 1,
+        GateState::Open => 2,
+        GateState::Closing => 3,
+        GateState::Stopped { .. } => 4,
+        GateState::Fault(_) => 5,
+    };
+    out[1] = movement(status.left.movement);
+    out[2] = movement(status.right.movement);
+    // Flags: bit0 = obstacle, bit1 = autoclose timer running, bit2 = stopped between end positions
+    out[3] = (if status.obstacle { 0x01 } else { 0 })
+        | (if status.autoclose_at.is_some() { 0x02 } else { 0 })
+        | (if status.state == (GateState::Stopped { partial: true }) { 0x04 } else { 0 });
+    let remaining = status
+        .autoclose_at
+        .map(|at| at.saturating_duration_since(now).as_millis().min(u32::MAX as u64) as u32)
+        .unwrap_or(0);
+    out[4..8].copy_from_slice(&remaining.to_le_bytes());
+    out[8..10].copy_from_slice(&status.left.position_at(now).to_le_bytes());
+    out[10..12].copy_from_slice(&status.right.position_at(now).to_le_bytes());
+    if let GateState::Fault(code) = status.state {
+        out[12] = code as u8;
+    }
+    out
+}
+
 /// Connection timeout task.
 /// Disconnects the client after 1 second unless they are an authenticated admin in admin mode.
 async fn connection_timeout_task(server: &Server<'_>, duration: u32) {
@@ -750,6 +1219,50 @@ async fn custom_task<C: Controller, P: PacketPool>(
 }
 */
 
+/// Legacy v1 challenge: `SHA256(server_nonce || client_nonce)`
+fn auth_digest_v1(server_nonce: &[u8; 32], client_nonce: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(server_nonce);
+    hasher.update(client_nonce);
+    hasher.finalize().into()
+}
+
+/// v2 challenge: `SHA256("BlueGate-auth-v2" || server_nonce || client_nonce || action (u16 LE) || device_id || client_pubkey)`
+fn auth_digest_v2(
+    server_nonce: &[u8; 32],
+    client_nonce: &[u8; 32],
+    action: u16,
+    device_id: &[u8; 6],
+    pubkey: &[u8; 33],
+) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(AUTH_DOMAIN_V2);
+    hasher.update(server_nonce);
+    hasher.update(client_nonce);
+    hasher.update(action.to_le_bytes());
+    hasher.update(device_id);
+    hasher.update(pubkey);
+    hasher.finalize().into()
+}
+
+/// Verify a 64-byte signature over `digest` with the client key (0x01 = Ed25519, 0x02/0x03 = secp256r1)
+fn verify_signature(digest: &[u8; 32], sig: &[u8], pubkey: &[u8; 33]) -> bool {
+    match pubkey[0] {
+        1 => {
+            let key32: &[u8; 32] = pubkey[1..33].try_into().unwrap();
+            let Ok(verifying_key) = VerifyingKey::from_bytes(key32) else {
+                return false;
+            };
+            let Ok(signature) = ed25519::Signature::from_slice(sig) else {
+                return false;
+            };
+            verifying_key.verify(digest, &signature).is_ok()
+        }
+        2 | 3 => verify_secp256r1_sha256(digest, sig, pubkey),
+        _ => false,
+    }
+}
+
 pub fn verify_secp256r1_sha256(hash: &[u8; 32], sig: &[u8], pk: &[u8; 33]) -> bool {
     // 1) Parse the compressed SEC1 public key (33 bytes, 0x02/0x03 + X)
     let verifying_key = match p256::ecdsa::VerifyingKey::from_sec1_bytes(pk) {
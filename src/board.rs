@@ -0,0 +1,54 @@
+//! Board profiles
+//!
+//! The relay board revisions wire the same inputs and outputs to different
+//! GPIOs. The profile is picked at start-up from `ConfigSlot::BoardProfile`,
+//! so one firmware image runs on every revision with a profile.
+//!
+//! Only rev 1 has a profile so far. Rev 2 is not supported yet: its profile
+//! needs the GPIO numbers from the rev 2 schematic, which is not at hand, and
+//! guessed pins could drive a relay on the wrong output.
+
+/// GPIO numbers of every logical input and output of a board revision
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BoardProfile {
+    pub name: &'static str,
+    pub lamp: u8,
+    pub left_open: u8,
+    pub left_close: u8,
+    pub right_open: u8,
+    pub right_close: u8,
+    pub control: u8,
+    pub obstacle: u8,
+    /// Programming mode jumper, read once at start-up
+    pub prog_mode: u8,
+    pub pedestrian: u8,
+    /// Left open, left close, right open, right close
+    pub limits: [u8; 4],
+}
+
+/// First relay board revision
+pub const REV1: BoardProfile = BoardProfile {
+    name: "rev1",
+    lamp: 7,
+    left_open: 2,
+    left_close: 1,
+    right_open: 3,
+    right_close: 4,
+    control: 15,
+    obstacle: 14,
+    prog_mode: 20,
+    pedestrian: 21,
+    limits: [18, 19, 22, 23],
+};
+
+/// Profiles by `ConfigSlot::BoardProfile` value
+/// Further revisions are appended here, with their pinout taken from the board schematic
+/// (rev 2 goes at index 1 once its schematic is available)
+pub const PROFILES: [BoardProfile; 1] = [REV1];
+
+impl BoardProfile {
+    /// Profile for a config value, unknown values fall back to rev 1
+    pub fn from_config(value: u32) -> Self {
+        PROFILES.get(value as usize).copied().unwrap_or(REV1)
+    }
+}
@@ -0,0 +1,170 @@
+//! Wall-clock time
+//!
+//! The board has no battery-backed RTC, so wall-clock time is kept as an offset
+//! on top of the uptime clock and set over BLE. After a reboot the clock is
+//! unsynced until a client sets it again.
+
+use core::cell::Cell;
+use core::fmt;
+
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+/// Unix time in ms at uptime 0, None until synced
+static OFFSET_MS: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+
+/// Size of the Current Time (Exact Time 256) encoding
+pub const CTS_LEN: usize = 10;
+
+/// Set the wall clock to `unix_ms` at the current uptime
+pub fn set_unix_ms(unix_ms: u64) {
+    let offset = unix_ms.saturating_sub(Instant::now().as_millis());
+    OFFSET_MS.lock(|o| o.set(Some(offset)));
+}
+
+/// Current Unix time in ms, None if the clock was never set
+pub fn now_unix_ms() -> Option<u64> {
+    OFFSET_MS
+        .lock(|o| o.get())
+        .map(|offset| offset + Instant::now().as_millis())
+}
+
+/// Current Unix time in seconds, None if the clock was never set
+pub fn now_unix() -> Option<u64> {
+    now_unix_ms().map(|ms| ms / 1000)
+}
+
+pub fn is_synced() -> bool {
+    OFFSET_MS.lock(|o| o.get()).is_some()
+}
+
+/// UTC calendar date and time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    /// 1..=12
+    pub month: u8,
+    /// 1..=31
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Current wall-clock time, None if the clock was never set
+    pub fn now() -> Option<Self> {
+        now_unix().map(Self::from_unix)
+    }
+
+    /// Convert from seconds since 1970-01-01T00:00:00Z
+    pub fn from_unix(secs: u64) -> Self {
+        let days = (secs / 86_400) as i64;
+        let rem = secs % 86_400;
+        // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z.rem_euclid(146_097);
+        let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+        let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u8;
+        let year = (yoe + era * 400 + if month <= 2 { 1 } else { 0 }) as u16;
+        Self {
+            year,
+            month,
+            day,
+            hour: (rem / 3_600) as u8,
+            minute: (rem % 3_600 / 60) as u8,
+            second: (rem % 60) as u8,
+        }
+    }
+
+    /// Convert to seconds since 1970-01-01T00:00:00Z, None if a field is out of range
+    pub fn to_unix(&self) -> Option<u64> {
+        if self.year < 1970
+            || !(1..=12).contains(&self.month)
+            || self.day == 0
+            || self.day > days_in_month(self.year, self.month)
+            || self.hour > 23
+            || self.minute > 59
+            || self.second > 59
+        {
+            return None;
+        }
+        let days = self.days_since_epoch();
+        Some(
+            days as u64 * 86_400
+                + self.hour as u64 * 3_600
+                + self.minute as u64 * 60
+                + self.second as u64,
+        )
+    }
+
+    /// Day of week, 1 = Monday .. 7 = Sunday (as in the Current Time characteristic)
+    pub fn weekday(&self) -> u8 {
+        // 1970-01-01 was a Thursday
+        ((self.days_since_epoch() + 3).rem_euclid(7) + 1) as u8
+    }
+
+    fn days_since_epoch(&self) -> i64 {
+        // Days-from-civil, inverse of `from_unix`
+        let y = self.year as i64 - if self.month <= 2 { 1 } else { 0 };
+        let m = self.month as i64;
+        let era = y.div_euclid(400);
+        let yoe = y.rem_euclid(400);
+        let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + self.day as i64 - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146_097 + doe - 719_468
+    }
+
+    /// Parse the Exact Time 256 layout of the Bluetooth Current Time characteristic:
+    /// year (u16 LE), month, day, hours, minutes, seconds, day of week, fractions256, adjust reason
+    pub fn from_cts_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < 7 {
+            return None;
+        }
+        let dt = Self {
+            year: u16::from_le_bytes([data[0], data[1]]),
+            month: data[2],
+            day: data[3],
+            hour: data[4],
+            minute: data[5],
+            second: data[6],
+        };
+        dt.to_unix().map(|_| dt)
+    }
+
+    /// Encode as Exact Time 256 (fractions and adjust reason are zero)
+    pub fn to_cts_bytes(&self) -> [u8; CTS_LEN] {
+        let mut out = [0u8; CTS_LEN];
+        out[0..2].copy_from_slice(&self.year.to_le_bytes());
+        out[2] = self.month;
+        out[3] = self.day;
+        out[4] = self.hour;
+        out[5] = self.minute;
+        out[6] = self.second;
+        out[7] = self.weekday();
+        out
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        4 | 6 | 9 | 11 => 30,
+        2 if (year.is_multiple_of(4) && !year.is_multiple_of(100)) || year.is_multiple_of(400) => 29,
+        2 => 28,
+        _ => 31,
+    }
+}
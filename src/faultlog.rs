@@ -0,0 +1,171 @@
+//! Fault history persisted to flash
+//!
+//! Faults raised by the FSM (`fsm::FAULT_EVENTS`) are appended to a
+//! `sequential_storage::queue` in their own flash range, like the auth log.
+//! The oldest record is dropped once `CAPACITY` is reached.
+
+use crate::clock::{self, DateTime};
+use crate::println;
+use crate::types::{FaultCode, FaultEvent};
+use embassy_time::Instant;
+use embedded_storage_async::nor_flash::{MultiwriteNorFlash, NorFlash};
+use sequential_storage::cache::NoCache;
+use sequential_storage::queue;
+
+/// Flash storage range for the fault history
+/// 8KB (2 pages) directly before the auth log range (0x3D6000..0x3DE000)
+const FLASH_RANGE: core::ops::Range<u32> = 0x3D4000..0x3D6000;
+
+/// Number of retained records, well within one of the two pages
+pub const CAPACITY: usize = 32;
+
+/// Serialized record length
+pub const RECORD_LEN: usize = 14;
+
+/// Record flags
+const FLAG_VALID: u8 = 0x01;
+const FLAG_TIME_UNSYNCED: u8 = 0x04;
+
+#[derive(Copy, Clone, Debug)]
+pub struct FaultRecord {
+    pub code: FaultCode,
+    /// Uptime when the fault was raised
+    pub uptime_ms: u64,
+    /// Wall-clock time of the fault, None if the clock was not synced
+    pub time: Option<DateTime>,
+}
+
+impl FaultRecord {
+    /// Record for a fault raised at `event.at`, dated back from the current wall-clock time
+    pub fn from_event(event: FaultEvent) -> Self {
+        let age_ms = Instant::now().saturating_duration_since(event.at).as_millis();
+        let time = clock::now_unix_ms().map(|unix_ms| DateTime::from_unix(unix_ms.saturating_sub(age_ms) / 1000));
+        Self {
+            code: event.code,
+            uptime_ms: event.at.as_millis(),
+            time,
+        }
+    }
+
+    /// Serialize to the wire/flash format
+    pub fn to_bytes(&self) -> [u8; RECORD_LEN] {
+        let mut out = [0u8; RECORD_LEN];
+        let unix = self.time.and_then(|t| t.to_unix()).unwrap_or(0) as u32;
+        out[0] = FLAG_VALID | if unix == 0 { FLAG_TIME_UNSYNCED } else { 0 };
+        out[1] = self.code as u8;
+        out[2..10].copy_from_slice(&self.uptime_ms.to_le_bytes());
+        out[10..14].copy_from_slice(&unix.to_le_bytes());
+        out
+    }
+
+    /// Parse a record written by `to_bytes`
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < RECORD_LEN || data[0] & FLAG_VALID == 0 {
+            return None;
+        }
+        let time = if data[0] & FLAG_TIME_UNSYNCED == 0 {
+            let unix = u32::from_le_bytes(data[10..14].try_into().unwrap());
+            Some(DateTime::from_unix(unix as u64))
+        } else {
+            None
+        };
+        Some(Self {
+            code: FaultCode::from_u8(data[1])?,
+            uptime_ms: u64::from_le_bytes(data[2..10].try_into().unwrap()),
+            time,
+        })
+    }
+}
+
+/// Flash-backed fault history, only the record count is kept in RAM
+pub struct FaultLog {
+    count: usize,
+}
+
+impl FaultLog {
+    /// Open the history, counting the records already in flash
+    pub async fn new<S: NorFlash>(flash: &mut S) -> Self {
+        let mut log = Self { count: 0 };
+        let mut records = [None; 1];
+        log.count = log.read_newest(flash, &mut records).await;
+        println!("Fault log: {} records", log.count);
+        log
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Append a record, dropping the oldest one if the history is full
+    pub async fn push<S: MultiwriteNorFlash>(
+        &mut self,
+        flash: &mut S,
+        record: FaultRecord,
+    ) -> Result<(), sequential_storage::Error<S::Error>> {
+        if self.count >= CAPACITY {
+            self.pop_oldest(flash).await;
+        }
+        let mut cache = NoCache::new();
+        queue::push(flash, FLASH_RANGE, &mut cache, &record.to_bytes(), true).await?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Fill `out` with the newest records, newest first
+    /// Returns the number of records in flash
+    pub async fn read_newest<S: NorFlash>(&self, flash: &mut S, out: &mut [Option<FaultRecord>]) -> usize {
+        out.fill(None);
+        let mut cache = NoCache::new();
+        let mut buf = [0u8; 32];
+        let mut iter = match queue::iter(flash, FLASH_RANGE, &mut cache).await {
+            Ok(iter) => iter,
+            Err(reason) => {
+                println!("ERROR: fault log iter failed: {:?}", reason);
+                return 0;
+            }
+        };
+        // Walk from the oldest record, shifting the newest ones to the front
+        let mut seen = 0;
+        loop {
+            match iter.next(&mut buf).await {
+                Ok(Some(data)) => {
+                    if !out.is_empty() {
+                        out.rotate_right(1);
+                        out[0] = FaultRecord::from_bytes(&data);
+                    }
+                    seen += 1;
+                }
+                Ok(None) => return seen,
+                Err(reason) => {
+                    println!("ERROR: fault log read failed: {:?}", reason);
+                    return seen;
+                }
+            }
+        }
+    }
+
+    /// Drop every record
+    pub async fn clear<S: MultiwriteNorFlash>(&mut self, flash: &mut S) {
+        while self.pop_oldest(flash).await {}
+        self.count = 0;
+    }
+
+    async fn pop_oldest<S: MultiwriteNorFlash>(&mut self, flash: &mut S) -> bool {
+        let mut cache = NoCache::new();
+        let mut buf = [0u8; 32];
+        match queue::pop(flash, FLASH_RANGE, &mut cache, &mut buf).await {
+            Ok(Some(_)) => {
+                self.count = self.count.saturating_sub(1);
+                true
+            }
+            Ok(None) => {
+                self.count = 0;
+                false
+            }
+            Err(reason) => {
+                println!("ERROR: fault log pop failed: {:?}", reason);
+                false
+            }
+        }
+    }
+}
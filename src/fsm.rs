@@ -4,23 +4,61 @@
 
 use embassy_futures::select::{select, select3, Either, Either3};
 use embassy_sync::{
-    blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal,
+    blocking_mutex::raw::CriticalSectionRawMutex,
+    channel::Channel,
+    pubsub::{PubSubChannel, Subscriber},
+    signal::Signal,
+    watch::Watch,
 };
 use embassy_time::{Instant, Duration, Timer};
 
-use crate::gpi::GPI_CHANNEL;
-use crate::gpo::commands;
-use crate::types::{Door, DoorConfig, FsmCommand, GateConfig, GateState, GpiEvent};
+use crate::gpi::{self, GPI_CHANNEL};
+use crate::gpo::{commands, RELAY_FAULT_SIGNAL};
+use crate::types::{
+    ControlAction, ControlMode, Door, DoorConfig, DoorMovement, DoorStatus, DriveMode, FaultCode, FaultEvent,
+    FsmCommand, GateConfig, GateState, GateStatus, GpiEvent, GpiEventKind, PulseCommand, StateChange, POSITION_OPEN,
+};
 
-use esp_println::println;
+use crate::println;
 /// Channel for sending commands to the FSM from external processes
 pub static FSM_COMMAND_CHANNEL: Channel<CriticalSectionRawMutex, FsmCommand, 4> = Channel::new();
 
 /// Signal to abort closing operation (used for obstacle detection)
 static ABORT_CLOSE_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
 
-/// Current gate state (for external monitoring if needed)
-static CURRENT_STATE: Signal<CriticalSectionRawMutex, GateState> = Signal::new();
+/// Limit switch trips per door (left, right), carrying the direction whose end was reached
+static LIMIT_SIGNALS: [Signal<CriticalSectionRawMutex, DoorMovement>; 2] = [Signal::new(), Signal::new()];
+
+/// Max number of `GATE_STATUS` receivers (one per BLE connection plus spare)
+/// Anonymous receivers and `get_state` do not count towards this limit
+pub const GATE_STATUS_RECEIVERS: usize = 4;
+
+/// Latest gate status, readable by any number of readers (BLE, logging)
+/// Published by the FSM only; reading never consumes the value
+pub static GATE_STATUS: Watch<CriticalSectionRawMutex, GateStatus, GATE_STATUS_RECEIVERS> =
+    Watch::new_with(GateStatus::new());
+
+/// Buffered state changes per subscriber; slow subscribers lag instead of blocking the FSM
+const STATE_EVENTS_CAP: usize = 8;
+/// Max number of `STATE_EVENTS` subscribers
+pub const STATE_EVENTS_SUBSCRIBERS: usize = 4;
+
+pub type StateSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, StateChange, STATE_EVENTS_CAP, STATE_EVENTS_SUBSCRIBERS, 1>;
+
+/// Every state transition, with timestamps
+static STATE_EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    StateChange,
+    STATE_EVENTS_CAP,
+    STATE_EVENTS_SUBSCRIBERS,
+    1,
+> = PubSubChannel::new();
+
+/// Faults raised by the FSM, recorded to the fault history by the BLE task as they come,
+/// with or without a connection; faults that do not fit are dropped from the history
+/// (the state still shows them)
+pub static FAULT_EVENTS: Channel<CriticalSectionRawMutex, FaultEvent, 4> = Channel::new();
 
 /// Helper to send a command to the FSM
 pub async fn send_fsm_command(cmd: FsmCommand) {
@@ -28,71 +66,317 @@ pub async fn send_fsm_command(cmd: FsmCommand) {
     FSM_COMMAND_CHANNEL.send(cmd).await;
 }
 
-/// Get the current gate state
+/// Get the current gate state (as last published by the FSM)
 pub fn get_state() -> GateState {
-    // Default to Closed if not yet signaled
-    CURRENT_STATE.try_take().unwrap_or(GateState::Closed)
+    GATE_STATUS.try_get().map(|s| s.state).unwrap_or(GateState::Closed)
+}
+
+/// Subscribe to state change events
+/// Returns None if all `STATE_EVENTS_SUBSCRIBERS` slots are taken
+pub fn subscribe_state() -> Option<StateSubscriber> {
+    STATE_EVENTS.subscriber().ok()
 }
 
-fn set_state(state: GateState) {
-    println!("FSM state {:?} -> {:?}", get_state(), state);
-    CURRENT_STATE.signal(state);
+/// Publish a transition; the FSM's own `state` variable stays authoritative
+fn publish_state(from: GateState, to: GateState) {
+    let at = Instant::now();
+    println!("FSM state {:?} -> {:?}", from, to);
+    update_status(|s| {
+        s.state = to;
+        s.since = at;
+        s.autoclose_at = None;
+    });
+    STATE_EVENTS.immediate_publisher().publish_immediate(StateChange { from, to, at });
+}
+
+/// Modify the published gate status
+fn update_status<F: Fn(&mut GateStatus)>(f: F) {
+    GATE_STATUS.sender().send_modify(|s| {
+        if let Some(status) = s {
+            f(status)
+        }
+    });
+}
+
+/// Movement and estimated position of a door, as last published
+fn door_status(door: Door) -> DoorStatus {
+    GATE_STATUS
+        .try_get()
+        .map(|s| *s.door(door))
+        .unwrap_or(DoorStatus::closed())
+}
+
+/// Whether both doors are stopped at `position`
+fn doors_at(position: u16) -> bool {
+    let now = Instant::now();
+    [Door::Left, Door::Right]
+        .iter()
+        .all(|&door| door_status(door).position_at(now) == position)
+}
+
+/// Publish the start of a door movement; `full_travel` is the duration of a complete travel
+fn start_movement(door: Door, movement: DoorMovement, full_travel: Duration) {
+    let now = Instant::now();
+    update_status(|s| s.door_mut(door).start(movement, full_travel, now));
+}
+
+/// Publish a door stop, keeping the estimated position reached
+fn stop_movement(door: Door) {
+    let now = Instant::now();
+    update_status(|s| s.door_mut(door).stop(now));
+}
+
+/// Publish a door stop at the end of a complete travel
+fn finish_movement(door: Door, position: u16) {
+    let now = Instant::now();
+    update_status(|s| s.door_mut(door).stop_at(position, now));
+}
+
+fn limit_signal(door: Door) -> &'static Signal<CriticalSectionRawMutex, DoorMovement> {
+    match door {
+        Door::Left => &LIMIT_SIGNALS[0],
+        Door::Right => &LIMIT_SIGNALS[1],
+    }
+}
+
+/// Position at the end of a travel in `movement` direction
+fn end_position(movement: DoorMovement) -> u16 {
+    match movement {
+        DoorMovement::Opening => POSITION_OPEN,
+        DoorMovement::Closing | DoorMovement::Stopped => 0,
+    }
+}
+
+/// Receive the next GPI event, tracking the obstacle level in the gate status
+/// Limit trips are passed to the moving door, or pin the position of an idle one
+/// Control gestures go through `GateConfig::control_actions`: commands are queued
+/// as if sent over BLE, only gestures mapped to a control pulse are returned
+async fn receive_gpi(config: &GateConfig) -> GpiEventKind {
+    loop {
+        let event = GPI_CHANNEL.receive().await;
+        if let GpiEventKind::Control(gesture) = event.kind {
+            match config.control_actions[gesture as usize] {
+                ControlAction::Pulse => return event.kind,
+                ControlAction::None => println!("Control {:?} ignored", gesture),
+                ControlAction::Command(cmd) => {
+                    println!("Control {:?}: {:?}", gesture, cmd);
+                    if FSM_COMMAND_CHANNEL.try_send(cmd).is_err() {
+                        println!("Command queue full, {:?} dropped", cmd);
+                    }
+                }
+            }
+            continue;
+        }
+        track_input(event);
+        return event.kind;
+    }
+}
+
+/// Update the gate status and door positions from an input event
+fn track_input(event: GpiEvent) {
+    match event.kind {
+        GpiEventKind::ObstacleDetected => {
+            // Stamped with when the input changed, not when the FSM got to it
+            update_status(|s| {
+                if !s.obstacle {
+                    s.obstacle_since = event.at;
+                }
+                s.obstacle = true;
+            });
+        }
+        GpiEventKind::ObstacleCleared => update_status(|s| s.obstacle = false),
+        GpiEventKind::LeftOpenLimit
+        | GpiEventKind::LeftCloseLimit
+        | GpiEventKind::RightOpenLimit
+        | GpiEventKind::RightCloseLimit => {
+            if let Some((door, end)) = event.kind.limit() {
+                if door_status(door).movement == DoorMovement::Stopped {
+                    finish_movement(door, end_position(end));
+                }
+                limit_signal(door).signal(end);
+            }
+        }
+        GpiEventKind::Control(_) | GpiEventKind::PedestrianPulse => {}
+    }
+}
+
+/// Enter the Fault state, queueing the fault for the history
+fn raise_fault(code: FaultCode) -> GateState {
+    println!("FAULT: {:?}", code);
+    let event = FaultEvent { code, at: Instant::now() };
+    if FAULT_EVENTS.try_send(event).is_err() {
+        println!("Fault history queue full, {:?} not recorded", code);
+    }
+    GateState::Fault(code)
+}
+
+/// Wait for the limit switch at the end of `movement` to trip
+async fn wait_limit(door: Door, movement: DoorMovement) {
+    while limit_signal(door).wait().await != movement {}
+}
+
+/// A door with limit switches did not reach its end position within its travel time
+struct TravelTimeout;
+
+/// Wait for a door to reach `target` once its relay is on, returns the position reached
+/// With limit switches a move to an end position runs until the switch trips and
+/// `full_travel` is only the timeout; other moves are timed by `travel` and cut
+/// short if a limit trips first
+async fn wait_travel(
+    door: Door,
+    movement: DoorMovement,
+    target: u16,
+    travel: Duration,
+    full_travel: Duration,
+    limit_switches: bool,
+) -> Result<u16, TravelTimeout> {
+    let to_limit = limit_switches && target == end_position(movement);
+    let timeout = if to_limit { full_travel } else { travel };
+    match select(Timer::after(timeout), wait_limit(door, movement)).await {
+        Either::First(_) if to_limit => {
+            println!("FAULT: {:?} door did not reach its limit switch", door);
+            Err(TravelTimeout)
+        }
+        Either::First(_) => Ok(target),
+        Either::Second(_) => Ok(end_position(movement)),
+    }
 }
 
 /// FSM task - main state machine for gate control
+#[cfg(feature = "esp")]
 #[embassy_executor::task]
 pub async fn fsm_task(config: GateConfig) {
+    run_fsm(config).await
+}
+
+/// FSM loop, also driven directly by the host simulation
+pub async fn run_fsm(config: GateConfig) -> ! {
     println!("FSM task started");
-    set_state(GateState::Closed);
+    let mut state = match config.validate() {
+        Ok(()) => GateState::Closed,
+        Err(code) => raise_fault(code),
+    };
+    // Direction of the last movement, for step-by-step resume after a stop
+    let mut last_move = GateState::Opening;
+    // StopAutoClose received while opening, applies to the following Open state
+    let mut autoclose_stopped = false;
+    // Pedestrian cycle: Opening only opens the pedestrian leaf, part way
+    let mut pedestrian = false;
+    // Assume the gate is closed at power-up; limit switches, if fitted,
+    // report their initial levels and correct this
+    GATE_STATUS.sender().send(GateStatus::new());
+    for signal in &LIMIT_SIGNALS {
+        signal.reset();
+    }
+    RELAY_FAULT_SIGNAL.reset();
+    publish_state(state, state);
 
     // Ensure all outputs are off at start
     commands::stop_all_doors().await;
     commands::lamp_off().await;
 
     loop {
-        match get_state() {
-            GateState::Closed => {
-                handle_closed_state(&config).await;
-            }
-            GateState::Opening => {
-                handle_opening_state(&config).await;
-            }
-            GateState::Open => {
-                handle_open_state(&config).await;
-            }
-            GateState::Closing => {
-                handle_closing_state(&config).await;
+        let handler = async {
+            match state {
+                GateState::Opening if config.drive_mode == DriveMode::Pulse => {
+                    pedestrian = false;
+                    pulse_move(PulseCommand::Open, POSITION_OPEN).await;
+                    GateState::Open
+                }
+                GateState::Closing if config.drive_mode == DriveMode::Pulse => {
+                    if close_blocked() {
+                        // Nothing was pulsed, so the gate is still open; another open
+                        // pulse would move a single-input controller
+                        return GateState::Open;
+                    }
+                    pulse_move(PulseCommand::Close, 0).await;
+                    GateState::Closed
+                }
+                GateState::Closed => handle_closed_state(&config, &mut pedestrian).await,
+                GateState::Opening => handle_opening_state(&config, &mut pedestrian, &mut autoclose_stopped).await,
+                GateState::Open => {
+                    handle_open_state(&config, core::mem::take(&mut autoclose_stopped), &mut pedestrian).await
+                }
+                GateState::Closing => handle_closing_state(&config, &mut pedestrian).await,
+                GateState::Stopped { partial } => {
+                    handle_stopped_state(&config, partial, last_move, &mut pedestrian).await
+                }
+                GateState::Fault(code) => handle_fault_state(&config, code).await,
             }
+        };
+        // An interlock violation reported by the GPO aborts any state
+        let next = match select(handler, RELAY_FAULT_SIGNAL.wait()).await {
+            Either::First(next) => next,
+            Either::Second(code) => raise_fault(code),
+        };
+        if matches!(state, GateState::Opening | GateState::Closing) {
+            last_move = state;
         }
+        publish_state(state, next);
+        state = next;
+    }
+}
+
+/// Pulse mode: the third-party controller moves the gate, the doors are assumed
+/// to end at `position`
+async fn pulse_move(command: PulseCommand, position: u16) {
+    println!("Pulse mode: {:?} pulse", command);
+    commands::pulse(command).await;
+    finish_movement(Door::Left, position);
+    finish_movement(Door::Right, position);
+}
+
+/// Stop request in pulse mode: the controller may still be moving the gate
+async fn stop_pulse(config: &GateConfig) {
+    if config.drive_mode == DriveMode::Pulse {
+        println!("Pulse mode: Stop pulse");
+        commands::pulse(PulseCommand::Stop).await;
     }
 }
 
 /// Handle the Closed state - wait for Open command or control pulse
-async fn handle_closed_state(_config: &GateConfig) {
+async fn handle_closed_state(config: &GateConfig, pedestrian: &mut bool) -> GateState {
     println!("Waiting for open command...");
 
     loop {
         // Wait for either FSM command or GPI event
-        match select(FSM_COMMAND_CHANNEL.receive(), GPI_CHANNEL.receive()).await {
+        match select(FSM_COMMAND_CHANNEL.receive(), receive_gpi(config)).await {
             Either::First(cmd) => match cmd {
                 FsmCommand::Open => {
-                    set_state(GateState::Opening);
-                    return;
+                    *pedestrian = false;
+                    return GateState::Opening;
+                }
+                FsmCommand::OpenPedestrian => {
+                    *pedestrian = true;
+                    return GateState::Opening;
                 }
                 FsmCommand::Close => {
                     // Already closed, ignore
                     println!("Already closed, ignoring Close command");
                 }
-                FsmCommand::StopAutoClose => {
+                FsmCommand::Stop if config.drive_mode == DriveMode::Pulse => {
+                    stop_pulse(config).await;
+                    return GateState::Stopped { partial: true };
+                }
+                FsmCommand::StopAutoClose | FsmCommand::Stop | FsmCommand::ClearFault => {
                     // Not relevant in closed state
                 }
             },
             Either::Second(event) => match event {
-                GpiEvent::ControlPulse => {
-                    set_state(GateState::Opening);
-                    return;
+                GpiEventKind::Control(_) => {
+                    *pedestrian = false;
+                    return GateState::Opening;
+                }
+                GpiEventKind::PedestrianPulse => {
+                    *pedestrian = true;
+                    return GateState::Opening;
                 }
-                GpiEvent::ObstacleDetected | GpiEvent::ObstacleCleared => {
+                GpiEventKind::ObstacleDetected
+                | GpiEventKind::ObstacleCleared
+                | GpiEventKind::LeftOpenLimit
+                | GpiEventKind::LeftCloseLimit
+                | GpiEventKind::RightOpenLimit
+                | GpiEventKind::RightCloseLimit => {
                     // Ignore obstacle events in closed state
                 }
             },
@@ -100,30 +384,143 @@ async fn handle_closed_state(_config: &GateConfig) {
     }
 }
 
-/// Handle the Opening state - open both doors in parallel
-async fn handle_opening_state(config: &GateConfig) {
-    println!("Starting opening sequence");
+/// Commands received while opening, applied once the doors are open
+#[derive(Default)]
+struct AfterOpening {
+    close: bool,
+    /// Open received during a pedestrian opening
+    open_fully: bool,
+}
 
-    // Start lamp blinking (1 second before door movement)
+/// Handle the Opening state - open both doors in parallel
+/// (only the pedestrian leaf, part way, in a pedestrian cycle)
+async fn handle_opening_state(config: &GateConfig, pedestrian: &mut bool, autoclose_stopped: &mut bool) -> GateState {
+    let left_target = open_target(config, Door::Left, *pedestrian);
+    let right_target = open_target(config, Door::Right, *pedestrian);
+    let now = Instant::now();
+    if door_status(Door::Left).position_at(now) >= left_target && door_status(Door::Right).position_at(now) >= right_target {
+        // Reversed before the close relays were switched on
+        println!("Doors already open");
+        commands::lamp_off().await;
+        return GateState::Open;
+    }
+    println!("Starting {}opening sequence", if *pedestrian { "pedestrian " } else { "" });
+    let mut after = AfterOpening::default();
+
+    // Fast blink for the prestart (1 second before door movement)
+    commands::lamp_prestart().await;
+    let prestart = Timer::after(config.lamp_prestart);
+    if let Either::Second(_) = select(prestart, wait_opening_stop(config, *pedestrian, autoclose_stopped, &mut after)).await {
+        // Nothing moved yet, but a reversal or a pedestrian opening left the doors part way
+        println!("Stopped before the doors moved");
+        commands::lamp_off().await;
+        return if doors_at(0) {
+            GateState::Closed
+        } else {
+            GateState::Stopped { partial: true }
+        };
+    }
     commands::lamp_on().await;
-    Timer::after(config.lamp_prestart).await;
 
     // Open both doors in parallel using join
-    let left_future = open_door(Door::Left, &config.left_door);
-    let right_future = open_door(Door::Right, &config.right_door);
+    let left_future = open_door(Door::Left, &config.left_door, left_target, config.limit_switches);
+    let right_future = open_door(Door::Right, &config.right_door, right_target, config.limit_switches);
 
     // Use embassy_futures::join to run both in parallel
-    embassy_futures::join::join(left_future, right_future).await;
+    let doors = embassy_futures::join::join(left_future, right_future);
+    let (left, right) = match select(doors, wait_opening_stop(config, *pedestrian, autoclose_stopped, &mut after)).await {
+        Either::First(results) => results,
+        Either::Second(_) => {
+            println!("Stopped while opening");
+            halt_doors().await;
+            commands::lamp_off().await;
+            return if doors_at(0) {
+                GateState::Closed
+            } else {
+                GateState::Stopped { partial: true }
+            };
+        }
+    };
+
+    if left.is_err() || right.is_err() {
+        return raise_fault(FaultCode::TravelTimeout);
+    }
 
     // Stop lamp after doors are fully open
     commands::lamp_off().await;
 
-    set_state(GateState::Open);
+    if after.close {
+        println!("Close requested while opening");
+        GateState::Closing
+    } else if after.open_fully {
+        println!("Open requested during pedestrian opening");
+        *pedestrian = false;
+        GateState::Opening
+    } else {
+        GateState::Open
+    }
+}
+
+/// Handle commands and events while opening, returns on a stop request
+/// Close, StopAutoClose and a full Open (in a pedestrian cycle) are remembered
+/// and applied once the doors are open
+async fn wait_opening_stop(config: &GateConfig, pedestrian: bool, autoclose_stopped: &mut bool, after: &mut AfterOpening) {
+    loop {
+        match select(FSM_COMMAND_CHANNEL.receive(), receive_gpi(config)).await {
+            Either::First(cmd) => match cmd {
+                FsmCommand::Stop => return,
+                FsmCommand::Close => after.close = true,
+                FsmCommand::StopAutoClose => *autoclose_stopped = true,
+                FsmCommand::Open => after.open_fully = pedestrian,
+                FsmCommand::OpenPedestrian | FsmCommand::ClearFault => {
+                    // Already opening
+                }
+            },
+            Either::Second(event) => match event {
+                GpiEventKind::Control(_) if config.control_mode == ControlMode::StepByStep => return,
+                GpiEventKind::Control(_) => after.open_fully = pedestrian,
+                GpiEventKind::PedestrianPulse
+                | GpiEventKind::ObstacleDetected
+                | GpiEventKind::ObstacleCleared
+                | GpiEventKind::LeftOpenLimit
+                | GpiEventKind::LeftCloseLimit
+                | GpiEventKind::RightOpenLimit
+                | GpiEventKind::RightCloseLimit => {
+                    // Ignore while opening
+                }
+            },
+        }
+    }
+}
+
+/// Position a door is opened to: fully open, or in a pedestrian cycle the
+/// pedestrian position for the pedestrian leaf and closed for the other one
+fn open_target(config: &GateConfig, door: Door, pedestrian: bool) -> u16 {
+    if !pedestrian {
+        POSITION_OPEN
+    } else if door == config.pedestrian_door {
+        config.pedestrian_position()
+    } else {
+        0
+    }
 }
 
-/// Open a single door with its timing configuration
-async fn open_door(door: Door, config: &DoorConfig) {
-    println!("Opening {:?} door", door);
+/// Switch off every door relay and mark both doors stopped
+async fn halt_doors() {
+    commands::stop_all_doors().await;
+    stop_movement(Door::Left);
+    stop_movement(Door::Right);
+}
+
+/// Open a single door up to `target` with its timing configuration
+/// Only the travel left from the estimated position is driven
+async fn open_door(door: Door, config: &DoorConfig, target: u16, limit_switches: bool) -> Result<(), TravelTimeout> {
+    let travel = door_status(door).remaining(DoorMovement::Opening, target, config.open_duration, Instant::now());
+    if travel.as_ticks() == 0 {
+        println!("{:?} door already open", door);
+        return Ok(());
+    }
+    println!("Opening {:?} door for {} ms", door, travel.as_millis());
 
     // Wait for open delay
     if config.open_delay.as_millis() > 0 {
@@ -131,112 +528,155 @@ async fn open_door(door: Door, config: &DoorConfig) {
     }
 
     // Start opening
+    limit_signal(door).reset();
     commands::start_opening(door).await;
+    start_movement(door, DoorMovement::Opening, config.open_duration);
 
-    // Wait for open duration
-    Timer::after(config.open_duration).await;
+    // Wait for the remaining travel or the open limit
+    let reached = wait_travel(door, DoorMovement::Opening, target, travel, config.open_duration, limit_switches).await;
 
     // Stop opening
     commands::stop_opening(door).await;
-    println!("{:?} door fully open", door);
+    let position = reached.inspect_err(|_| stop_movement(door))?;
+    finish_movement(door, position);
+    println!("{:?} door open ({}/{})", door, position, POSITION_OPEN);
+    Ok(())
 }
 
 /// Handle the Open state - wait for close command or autoclose timeout
-async fn handle_open_state(config: &GateConfig) {
-    println!("Doors fully open");
+async fn handle_open_state(config: &GateConfig, autoclose_stopped: bool, pedestrian: &mut bool) -> GateState {
+    if *pedestrian {
+        println!("Pedestrian leaf open");
+    } else {
+        println!("Doors fully open");
+    }
 
-    let mut autoclose_enabled = config.autoclose_delay.is_some();
-    let mut do_close : u8 = 0;
-    let mut last_close_attempt = Instant::now().as_millis();
+    let mut autoclose_enabled = config.autoclose_delay.is_some() && !autoclose_stopped;
 
     loop {
         if autoclose_enabled {
             if let Some(delay) = config.autoclose_delay {
                 println!("Autoclose enabled, waiting {} seconds", delay.as_secs());
+                let deadline = Instant::now() + delay;
+                update_status(|s| s.autoclose_at = Some(deadline));
 
                 // Wait for autoclose timeout, command, or GPI event
                 match select3(
                     Timer::after(delay),
                     FSM_COMMAND_CHANNEL.receive(),
-                    GPI_CHANNEL.receive(),
+                    receive_gpi(config),
                 )
                 .await
                 {
                     Either3::First(_) => {
                         // Autoclose timeout expired
                         println!("Autoclose timeout, starting close sequence");
-                        set_state(GateState::Closing);
-                        return;
+                        return GateState::Closing;
                     }
                     Either3::Second(cmd) => match cmd {
-                        FsmCommand::Open => {
+                        FsmCommand::Open if *pedestrian => {
+                            *pedestrian = false;
+                            return GateState::Opening;
+                        }
+                        FsmCommand::Open | FsmCommand::OpenPedestrian => {
                             // Already open, reset autoclose timer
                             println!("Open command while open, resetting autoclose timer");
                             continue;
                         }
+                        FsmCommand::ClearFault => {
+                            continue;
+                        }
                         FsmCommand::Close => {
-                            set_state(GateState::Closing);
-                            return;
+                            return GateState::Closing;
                         }
                         FsmCommand::StopAutoClose => {
                             println!("Autoclose disabled");
                             autoclose_enabled = false;
+                            update_status(|s| s.autoclose_at = None);
                             continue;
                         }
+                        FsmCommand::Stop => {
+                            println!("Stop while open, holding open");
+                            stop_pulse(config).await;
+                            return GateState::Stopped { partial: !doors_at(POSITION_OPEN) };
+                        }
                     },
                     Either3::Third(event) => match event {
-                        GpiEvent::ControlPulse => {
+                        GpiEventKind::Control(_) if config.control_mode == ControlMode::StepByStep => {
+                            return GateState::Closing;
+                        }
+                        GpiEventKind::Control(_) if *pedestrian => {
+                            *pedestrian = false;
+                            return GateState::Opening;
+                        }
+                        GpiEventKind::PedestrianPulse => {
+                            println!("Pedestrian pulse while open, resetting autoclose timer");
+                            continue;
+                        }
+                        GpiEventKind::Control(_) => {
                             println!("Control pulse while open, resetting autoclose timer");
                             // Control pulse while open = close
                             // set_state(GateState::Closing);
                             continue;
                         }
-                        GpiEvent::ObstacleDetected => {
+                        GpiEventKind::ObstacleDetected => {
                             println!("Obstacle event while open, resetting autoclose timer");
                             // Ignore obstacle events in open state
                             continue;
                         }
-                        GpiEvent::ObstacleCleared => {
+                        GpiEventKind::ObstacleCleared => {
                             println!("Obstacle cleared, resetting autoclose timer");
                             continue;
                         }
+                        GpiEventKind::LeftOpenLimit
+                        | GpiEventKind::LeftCloseLimit
+                        | GpiEventKind::RightOpenLimit
+                        | GpiEventKind::RightCloseLimit => {
+                            continue;
+                        }
                     },
                 }
             }
         } else {
             // Autoclose disabled, wait for explicit close command
-            match select(FSM_COMMAND_CHANNEL.receive(), GPI_CHANNEL.receive()).await {
+            match select(FSM_COMMAND_CHANNEL.receive(), receive_gpi(config)).await {
                 Either::First(cmd) => match cmd {
-                    FsmCommand::Open => {
+                    FsmCommand::Open if *pedestrian => {
+                        *pedestrian = false;
+                        return GateState::Opening;
+                    }
+                    FsmCommand::Open | FsmCommand::OpenPedestrian => {
                         // Already open, ignore
                     }
                     FsmCommand::Close => {
-                        set_state(GateState::Closing);
-                        return;
+                        return GateState::Closing;
                     }
-                    FsmCommand::StopAutoClose => {
+                    FsmCommand::StopAutoClose | FsmCommand::ClearFault => {
                         // Already disabled
                     }
+                    FsmCommand::Stop => {
+                        println!("Stop while open, holding open");
+                        stop_pulse(config).await;
+                        return GateState::Stopped { partial: !doors_at(POSITION_OPEN) };
+                    }
                 },
                 Either::Second(event) => match event {
-                    GpiEvent::ControlPulse => {
-                        // Control pulse while open = close
-                        // call 3 times with less than 10 sec interval to close
-                        let now = Instant::now().as_millis();
-                        if (now-last_close_attempt) < 10000 {
-                            do_close += 1;
-                        }else{
-                            do_close = 1;
-                        }
-                        last_close_attempt = now;
-                        println!("Autoclose disabled, control pulse {} of 3",do_close);
-                        if do_close > 2 {
-                            set_state(GateState::Closing);
-                            return;
-                        }
+                    GpiEventKind::Control(_) if config.control_mode == ControlMode::StepByStep => {
+                        return GateState::Closing;
                     }
-                    GpiEvent::ObstacleDetected | GpiEvent::ObstacleCleared => {
-                        // Ignore obstacle events in open state
+                    GpiEventKind::Control(_) if *pedestrian => {
+                        *pedestrian = false;
+                        return GateState::Opening;
+                    }
+                    GpiEventKind::Control(_)
+                    | GpiEventKind::PedestrianPulse
+                    | GpiEventKind::ObstacleDetected
+                    | GpiEventKind::ObstacleCleared
+                    | GpiEventKind::LeftOpenLimit
+                    | GpiEventKind::LeftCloseLimit
+                    | GpiEventKind::RightOpenLimit
+                    | GpiEventKind::RightCloseLimit => {
+                        // Ignored; closing takes a Close command or a gesture mapped to it (triple click by default)
                     }
                 },
             }
@@ -245,20 +685,46 @@ async fn handle_open_state(config: &GateConfig) {
 }
 
 /// Handle the Closing state - close both doors in parallel, monitor for obstacles
-async fn handle_closing_state(config: &GateConfig) {
+async fn handle_closing_state(config: &GateConfig, pedestrian: &mut bool) -> GateState {
+    if let (Some(limit), Some(status)) = (config.obstacle_stuck_after, GATE_STATUS.try_get()) {
+        if status.obstacle && Instant::now().saturating_duration_since(status.obstacle_since) > limit {
+            println!("Obstacle input active for over {} s", limit.as_secs());
+            return raise_fault(FaultCode::SensorStuck);
+        }
+    }
+    if close_blocked() {
+        // Like an obstacle during the prestart; Opening returns to Open if the doors are fully open
+        return GateState::Opening;
+    }
     println!("Starting closing sequence");
 
-    // Start lamp blinking (1 second before door movement)
+    // Fast blink for the prestart (1 second before door movement)
+    commands::lamp_prestart().await;
+
+    // Nothing moves during the prestart
+    if let Either::Second(interrupt) = select(Timer::after(config.lamp_prestart), obstacle_monitor_task(config, pedestrian)).await {
+        return match interrupt {
+            CloseInterrupt::Reverse => {
+                // Opening returns to Open right away if the doors are fully open
+                println!("Close cancelled before the doors moved");
+                GateState::Opening
+            }
+            CloseInterrupt::Stop => {
+                commands::lamp_off().await;
+                GateState::Stopped { partial: !doors_at(POSITION_OPEN) }
+            }
+        };
+    }
+
     commands::lamp_on().await;
-    Timer::after(config.lamp_prestart).await;
 
     // Reset abort signal
     ABORT_CLOSE_SIGNAL.reset();
 
     // Close both doors in parallel, monitoring for obstacles
-    let left_future = close_door_with_obstacle_monitor(Door::Left, &config.left_door);
-    let right_future = close_door_with_obstacle_monitor(Door::Right, &config.right_door);
-    let obstacle_monitor = obstacle_monitor_task();
+    let left_future = close_door_with_obstacle_monitor(Door::Left, &config.left_door, config.limit_switches);
+    let right_future = close_door_with_obstacle_monitor(Door::Right, &config.right_door, config.limit_switches);
+    let obstacle_monitor = obstacle_monitor_task(config, pedestrian);
 
     // Run closing and obstacle monitoring in parallel
     let result = select(
@@ -268,35 +734,60 @@ async fn handle_closing_state(config: &GateConfig) {
     .await;
 
     match result {
-        Either::First(_) => {
+        Either::First((Ok(()), Ok(()))) => {
             // Doors closed successfully
             commands::lamp_off().await;
-            set_state(GateState::Closed);
+            GateState::Closed
         }
-        Either::Second(_) => {
-            // Obstacle detected during close
-            println!("Obstacle detected during close, reversing!");
-
+        Either::First(_) => raise_fault(FaultCode::TravelTimeout),
+        Either::Second(interrupt) => {
             // Stop closing immediately
             commands::stop_closing(Door::Left).await;
             commands::stop_closing(Door::Right).await;
+            stop_movement(Door::Left);
+            stop_movement(Door::Right);
 
             // Signal abort to any waiting close operations
             ABORT_CLOSE_SIGNAL.signal(());
 
-            // Small delay before reversing
-            Timer::after(Duration::from_millis(100)).await;
+            match interrupt {
+                CloseInterrupt::Reverse => {
+                    // Obstacle detected during close
+                    println!("Obstacle detected during close, reversing!");
 
-            // Transition to opening state (reverse)
-            set_state(GateState::Opening);
+                    // Transition to opening state (reverse), the GPO task holds the
+                    // open relays back for the relay dead time
+                    GateState::Opening
+                }
+                CloseInterrupt::Stop => {
+                    println!("Stopped while closing");
+                    commands::lamp_off().await;
+                    GateState::Stopped { partial: !doors_at(POSITION_OPEN) }
+                }
+            }
         }
     }
 }
 
+/// A close is not started while the obstacle input is active
+fn close_blocked() -> bool {
+    let blocked = gpi::is_obstacle_active();
+    if blocked {
+        println!("Obstacle present, close refused");
+    }
+    blocked
+}
+
 /// Close a single door with its timing configuration
+/// Only the travel left from the estimated position is driven
 /// This task can be aborted by the obstacle detection
-async fn close_door_with_obstacle_monitor(door: Door, config: &DoorConfig) {
-    println!("Closing {:?} door", door);
+async fn close_door_with_obstacle_monitor(door: Door, config: &DoorConfig, limit_switches: bool) -> Result<(), TravelTimeout> {
+    let travel = door_status(door).remaining(DoorMovement::Closing, 0, config.close_duration, Instant::now());
+    if travel.as_ticks() == 0 {
+        println!("{:?} door already closed", door);
+        return Ok(());
+    }
+    println!("Closing {:?} door for {} ms", door, travel.as_millis());
 
     // Wait for close delay (check for abort during this time too)
     if config.close_delay.as_millis() > 0 {
@@ -304,58 +795,96 @@ async fn close_door_with_obstacle_monitor(door: Door, config: &DoorConfig) {
             Either::First(_) => {}
             Either::Second(_) => {
                 println!("{:?} door close aborted during delay", door);
-                return;
+                return Ok(());
             }
         }
     }
 
     // Start closing
+    limit_signal(door).reset();
     commands::start_closing(door).await;
+    start_movement(door, DoorMovement::Closing, config.close_duration);
 
-    // Wait for close duration or abort
+    // Wait for the remaining travel (or the close limit) or abort
     match select(
-        Timer::after(config.close_duration),
+        wait_travel(door, DoorMovement::Closing, 0, travel, config.close_duration, limit_switches),
         ABORT_CLOSE_SIGNAL.wait(),
     )
     .await
     {
-        Either::First(_) => {
+        Either::First(Ok(position)) => {
             // Normal completion
             commands::stop_closing(door).await;
+            finish_movement(door, position);
             println!("{:?} door fully closed", door);
+            Ok(())
+        }
+        Either::First(Err(timeout)) => {
+            commands::stop_closing(door).await;
+            stop_movement(door);
+            Err(timeout)
         }
         Either::Second(_) => {
             // Aborted
             commands::stop_closing(door).await;
+            stop_movement(door);
             println!("{:?} door close aborted", door);
+            Ok(())
         }
     }
 }
 
+/// Why a close sequence was interrupted
+enum CloseInterrupt {
+    /// Obstacle or open command: reopen (fully after Open, as before otherwise)
+    Reverse,
+    /// Stop command or step-by-step control pulse: halt
+    Stop,
+}
+
 /// Monitor for obstacles during closing sequence
-/// Returns when an obstacle is detected
-async fn obstacle_monitor_task() {
+/// Returns when an obstacle is detected or the close is stopped
+async fn obstacle_monitor_task(config: &GateConfig, pedestrian: &mut bool) -> CloseInterrupt {
     loop {
         // Also check for commands that might come in
-        match select(GPI_CHANNEL.receive(), FSM_COMMAND_CHANNEL.receive()).await {
+        match select(receive_gpi(config), FSM_COMMAND_CHANNEL.receive()).await {
             Either::First(event) => match event {
-                GpiEvent::ObstacleDetected => {
+                GpiEventKind::ObstacleDetected => {
                     println!("Obstacle monitor: obstacle detected!");
-                    return;
+                    return CloseInterrupt::Reverse;
                 }
-                GpiEvent::ControlPulse | GpiEvent::ObstacleCleared => {
+                GpiEventKind::Control(_) if config.control_mode == ControlMode::StepByStep => {
+                    return CloseInterrupt::Stop;
+                }
+                GpiEventKind::PedestrianPulse => {
+                    println!("Pedestrian pulse during close - reversing");
+                    return CloseInterrupt::Reverse;
+                }
+                GpiEventKind::Control(_)
+                | GpiEventKind::ObstacleCleared
+                | GpiEventKind::LeftOpenLimit
+                | GpiEventKind::LeftCloseLimit
+                | GpiEventKind::RightOpenLimit
+                | GpiEventKind::RightCloseLimit => {
                     // Ignore other events during close monitoring
                 }
             },
             Either::Second(cmd) => {
-                // Could handle emergency commands here if needed
                 match cmd {
                     FsmCommand::Open => {
                         // Treat open command as obstacle - reverse
                         println!("Open command during close - reversing");
-                        return;
+                        *pedestrian = false;
+                        return CloseInterrupt::Reverse;
+                    }
+                    FsmCommand::OpenPedestrian => {
+                        println!("Pedestrian open during close - reversing");
+                        return CloseInterrupt::Reverse;
                     }
-                    FsmCommand::Close | FsmCommand::StopAutoClose => {
+                    FsmCommand::Stop => {
+                        return CloseInterrupt::Stop;
+                    }
+                    FsmCommand::Close | FsmCommand::StopAutoClose | FsmCommand::ClearFault => {
                         // Ignore
                     }
                 }
@@ -363,3 +892,94 @@ async fn obstacle_monitor_task() {
         }
     }
 }
+
+/// Handle the Stopped state - doors halted, wait for the next command
+async fn handle_stopped_state(config: &GateConfig, partial: bool, last_move: GateState, pedestrian: &mut bool) -> GateState {
+    if partial {
+        println!("Gate stopped between end positions");
+    } else {
+        println!("Gate held open");
+    }
+
+    loop {
+        match select(FSM_COMMAND_CHANNEL.receive(), receive_gpi(config)).await {
+            Either::First(cmd) => match cmd {
+                FsmCommand::Open => {
+                    *pedestrian = false;
+                    return if partial { GateState::Opening } else { GateState::Open };
+                }
+                FsmCommand::OpenPedestrian => {
+                    return resume_pedestrian(partial, pedestrian);
+                }
+                FsmCommand::Close => {
+                    return GateState::Closing;
+                }
+                FsmCommand::Stop | FsmCommand::StopAutoClose | FsmCommand::ClearFault => {
+                    // Already stopped
+                }
+            },
+            Either::Second(event) => match event {
+                GpiEventKind::PedestrianPulse => {
+                    return resume_pedestrian(partial, pedestrian);
+                }
+                GpiEventKind::Control(_) if config.control_mode == ControlMode::Standard => {
+                    *pedestrian = false;
+                    return if partial { GateState::Opening } else { GateState::Open };
+                }
+                GpiEventKind::Control(_) => {
+                    // Step-by-step: held fully open the next step closes,
+                    // otherwise continue in the opposite direction
+                    return if !partial || last_move == GateState::Opening {
+                        GateState::Closing
+                    } else {
+                        GateState::Opening
+                    };
+                }
+                GpiEventKind::ObstacleDetected
+                | GpiEventKind::ObstacleCleared
+                | GpiEventKind::LeftOpenLimit
+                | GpiEventKind::LeftCloseLimit
+                | GpiEventKind::RightOpenLimit
+                | GpiEventKind::RightCloseLimit => {
+                    // Ignore obstacle events while stopped
+                }
+            },
+        }
+    }
+}
+
+/// Handle the Fault state - relays off, lamp flashing the fault code until cleared
+async fn handle_fault_state(config: &GateConfig, code: FaultCode) -> GateState {
+    halt_doors().await;
+    commands::lamp_fault(code).await;
+
+    loop {
+        match select(FSM_COMMAND_CHANNEL.receive(), receive_gpi(config)).await {
+            Either::First(FsmCommand::ClearFault) => {
+                println!("Fault {:?} cleared", code);
+                commands::lamp_off().await;
+                return if doors_at(0) {
+                    GateState::Closed
+                } else {
+                    GateState::Stopped { partial: !doors_at(POSITION_OPEN) }
+                };
+            }
+            Either::First(cmd) => {
+                println!("Fault {:?} active, ignoring {:?}", code, cmd);
+            }
+            Either::Second(_) => {
+                // Inputs are ignored, positions are tracked by receive_gpi
+            }
+        }
+    }
+}
+
+/// Pedestrian open while stopped: resume when held fully open, else open the pedestrian leaf
+fn resume_pedestrian(partial: bool, pedestrian: &mut bool) -> GateState {
+    if partial {
+        *pedestrian = true;
+        GateState::Opening
+    } else {
+        GateState::Open
+    }
+}
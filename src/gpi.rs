@@ -1,134 +1,308 @@
 //! GPI (General Purpose Input) module
 //!
 //! Monitors input pins with debouncing and generates events for the FSM.
+//! Every input waits for its own edges instead of being polled: a new level is
+//! accepted once it has held for that input's debounce time, and the event is
+//! stamped with when the input changed. The accepted levels are also kept in a
+//! shared snapshot for synchronous checks and for reading over BLE.
 
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use core::cell::Cell;
+use core::future::pending;
+
+use embassy_futures::join::join4;
+use embassy_futures::select::{select, Either};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_sync::channel::Channel;
 use embassy_time::{Duration, Instant, Timer};
-use esp_hal::gpio::{Input };
+use embedded_hal::digital::InputPin;
+use embedded_hal_async::digital::Wait;
+#[cfg(feature = "esp")]
+use esp_hal::gpio::Input;
 
-use crate::types::GpiEvent;
-use esp_println::println;
+use crate::types::{ControlGesture, GpiConfig, GpiEvent, GpiEventKind, InputLevels};
+use crate::println;
 
 /// Channel for sending events from GPI to FSM
 pub static GPI_CHANNEL: Channel<CriticalSectionRawMutex, GpiEvent, 8> = Channel::new();
 
-/// Debounce configuration
-const DEBOUNCE_TIME: Duration = Duration::from_millis(100);
-const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Debounced input levels, updated as each change is accepted
+static INPUT_LEVELS: Mutex<CriticalSectionRawMutex, Cell<InputLevels>> = Mutex::new(Cell::new(InputLevels {
+    active: 0,
+    fitted: 0,
+}));
+
+/// Obstacle reports repeat at this interval while the input stays active
 const OBSTACLE_REPEAT: Duration = Duration::from_secs(3);
 
-/// Debouncer state for a single input
-struct Debouncer {
-    stable_state: bool,
-    last_change: Instant,
-    pending_state: bool,
+/// Limit switch events, in the order of the limit pins passed to `run_gpi`
+const LIMIT_EVENTS: [GpiEventKind; 4] = [
+    GpiEventKind::LeftOpenLimit,
+    GpiEventKind::LeftCloseLimit,
+    GpiEventKind::RightOpenLimit,
+    GpiEventKind::RightCloseLimit,
+];
+
+/// Debounced level of a single input
+struct DebouncedInput<P> {
+    pin: P,
+    /// `InputLevels` bit, also the polarity bit
+    bit: u8,
+    active_high: bool,
+    /// Accepted level
+    active: bool,
+    /// Time the active / inactive level must hold before it is accepted
+    debounce_on: Duration,
+    debounce_off: Duration,
 }
 
-impl Debouncer {
-    fn new(initial_state: bool) -> Self {
+impl<P: InputPin + Wait> DebouncedInput<P> {
+    /// Input starting inactive, so one already active at start reports at once
+    fn new(pin: P, bit: u8, polarity: u32, debounce: Duration) -> Self {
         Self {
-            stable_state: initial_state,
-            last_change: Instant::now(),
-            pending_state: initial_state,
+            pin,
+            bit,
+            active_high: polarity & bit as u32 != 0,
+            active: false,
+            debounce_on: debounce,
+            debounce_off: debounce,
         }
     }
 
-    /// Update the debouncer with a new raw reading
-    /// Returns Some(true) if rising edge detected, Some(false) if falling edge
-    fn update(&mut self, raw_state: bool) -> Option<bool> {
-        let now = Instant::now();
-
-        if raw_state != self.pending_state {
-            // State changed, reset timer
-            self.pending_state = raw_state;
-            self.last_change = now;
-            None
-        } else if raw_state != self.stable_state {
-            // State is different from stable but matches pending
-            if now.duration_since(self.last_change) >= DEBOUNCE_TIME {
-                // Debounce time passed, accept new state
-                self.stable_state = raw_state;
-                Some(raw_state)
-            } else {
-                None
-            }
+    /// Take the current level as accepted, an input held at start is not reported
+    fn with_current_level(mut self) -> Self {
+        let active = is_active(&mut self.pin, self.active_high);
+        self.accept(active);
+        self
+    }
+
+    fn accept(&mut self, active: bool) {
+        self.active = active;
+        INPUT_LEVELS.lock(|levels| {
+            let mut l = levels.get();
+            l.active = if active { l.active | self.bit } else { l.active & !self.bit };
+            levels.set(l);
+        });
+    }
+
+    /// Wait until the input shows `active`
+    /// Level waits return at once if the pin is already there, so no edge is missed
+    async fn wait_level(&mut self, active: bool) {
+        let _ = if active == self.active_high {
+            self.pin.wait_for_high().await
         } else {
-            None
+            self.pin.wait_for_low().await
+        };
+    }
+
+    /// Wait for the next accepted level change
+    /// Returns the new level and when the input changed to it
+    async fn next_change(&mut self) -> (bool, Instant) {
+        let target = !self.active;
+        let debounce = if target { self.debounce_on } else { self.debounce_off };
+        loop {
+            self.wait_level(target).await;
+            let changed_at = Instant::now();
+            if let Either::First(_) = select(Timer::after(debounce), self.wait_level(!target)).await {
+                self.accept(target);
+                return (target, changed_at);
+            }
+            // Bounced back within the debounce time, start over
         }
     }
+}
+
+/// Read an input as active/inactive
+/// Polarity bit clear = active low, set = active high; read errors count as inactive
+fn is_active<P: InputPin>(pin: &mut P, active_high: bool) -> bool {
+    let high = pin.is_high().unwrap_or(!active_high);
+    high == active_high
+}
 
-    // fn is_active(&self) -> bool {
-    //     self.stable_state
-    // }
+async fn send_event(kind: GpiEventKind, at: Instant) {
+    println!("GPI: {:?}", kind);
+    GPI_CHANNEL.send(GpiEvent { kind, at }).await;
 }
 
-/// GPI task - monitors control and obstacle inputs
+/// Report each activation of a pulse input (pedestrian, limit switch) as `kind`
+async fn watch_pulses<P: InputPin + Wait>(input: &mut DebouncedInput<P>, kind: GpiEventKind) {
+    loop {
+        let (active, at) = input.next_change().await;
+        // Releases are not reported
+        if active {
+            send_event(kind, at).await;
+        }
+    }
+}
+
+/// Wait for the next gesture on the control input
+/// Returns the gesture and when its first press started
+async fn next_gesture<P: InputPin + Wait>(
+    input: &mut DebouncedInput<P>,
+    long_press: Duration,
+    click_gap: Duration,
+) -> (ControlGesture, Instant) {
+    // A press still held (long press, or held at start) is released first
+    if input.active {
+        input.next_change().await;
+    }
+    let (_, started) = input.next_change().await;
+    let long_press = (long_press.as_ticks() > 0).then_some(long_press);
+    let click_gap = (click_gap.as_ticks() > 0).then_some(click_gap);
+    if long_press.is_none() && click_gap.is_none() {
+        return (ControlGesture::ShortPress, started);
+    }
+    match long_press {
+        Some(hold) => {
+            if let Either::First(_) = select(Timer::at(started + hold), input.next_change()).await {
+                return (ControlGesture::LongPress, started);
+            }
+        }
+        None => {
+            input.next_change().await;
+        }
+    }
+    // Released: count further clicks that follow within the gap
+    let mut clicks = 1;
+    if let Some(gap) = click_gap {
+        while clicks < 3 {
+            if let Either::First(_) = select(Timer::after(gap), input.next_change()).await {
+                break;
+            }
+            input.next_change().await;
+            clicks += 1;
+        }
+    }
+    let gesture = match clicks {
+        1 => ControlGesture::ShortPress,
+        2 => ControlGesture::DoubleClick,
+        _ => ControlGesture::TripleClick,
+    };
+    (gesture, started)
+}
+
+/// Report the gestures of the control input
+async fn watch_control<P: InputPin + Wait>(input: &mut DebouncedInput<P>, long_press: Duration, click_gap: Duration) {
+    loop {
+        let (gesture, at) = next_gesture(input, long_press, click_gap).await;
+        send_event(GpiEventKind::Control(gesture), at).await;
+    }
+}
+
+/// Report the obstacle input, repeating `ObstacleDetected` while it stays active
+/// Repeats carry the time the obstacle appeared
+async fn watch_obstacle<P: InputPin + Wait>(input: &mut DebouncedInput<P>) {
+    loop {
+        let (_, since) = input.next_change().await;
+        send_event(GpiEventKind::ObstacleDetected, since).await;
+        loop {
+            match select(Timer::after(OBSTACLE_REPEAT), input.next_change()).await {
+                Either::First(_) => send_event(GpiEventKind::ObstacleDetected, since).await,
+                Either::Second((_, at)) => {
+                    send_event(GpiEventKind::ObstacleCleared, at).await;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// GPI task - monitors control, obstacle and optional pedestrian inputs
 ///
 /// Generates events when debounced state changes occur.
-/// Control input: generates ControlPulse on rising edge
+/// Control input: generates a Control event per gesture (short/long press, double/triple click)
 /// Obstacle input: generates ObstacleDetected/ObstacleCleared on state changes
+/// Pedestrian input: generates PedestrianPulse on rising edge
+/// Limit switches (left open, left close, right open, right close): generate a
+/// limit event on rising edge, including a switch already active at start
+#[cfg(feature = "esp")]
 #[embassy_executor::task]
-pub async fn gpi_task(control_pin: Input<'static>, obstacle_pin: Input<'static>, polarity: u32) {
+pub async fn gpi_task(
+    control_pin: Input<'static>,
+    obstacle_pin: Input<'static>,
+    pedestrian_pin: Option<Input<'static>>,
+    limit_pins: Option<[Input<'static>; 4]>,
+    config: GpiConfig,
+) {
+    run_gpi(control_pin, obstacle_pin, pedestrian_pin, limit_pins, config).await
+}
+
+/// GPI loop, generic over the input pins so it can also run in the host simulation
+pub async fn run_gpi<C, O, P, L>(
+    control_pin: C,
+    obstacle_pin: O,
+    pedestrian_pin: Option<P>,
+    limit_pins: Option<[L; 4]>,
+    config: GpiConfig,
+) -> !
+where
+    C: InputPin + Wait,
+    O: InputPin + Wait,
+    P: InputPin + Wait,
+    L: InputPin + Wait,
+{
+    let polarity = config.polarity;
     println!("GPI task started polarity {}",polarity & 255);
 
-    // Initialize debouncers with current pin states
-    // Assuming active high for both inputs (adjust as needed)
-    let mut control_debouncer = Debouncer::new(if polarity&1==0 {control_pin.is_low()}else{control_pin.is_high()});
-    let mut obstacle_debouncer = Debouncer::new(if polarity&2==0 {obstacle_pin.is_low()}else{obstacle_pin.is_high()});
-    let mut last_obstacle_report: Option<Instant> = None;
+    let mut fitted = InputLevels::CONTROL | InputLevels::OBSTACLE;
+    if pedestrian_pin.is_some() {
+        fitted |= InputLevels::PEDESTRIAN;
+    }
+    if limit_pins.is_some() {
+        fitted |= InputLevels::LIMITS.iter().fold(0, |bits, bit| bits | bit);
+    }
+    INPUT_LEVELS.lock(|levels| levels.set(InputLevels { active: 0, fitted }));
 
-    loop {
-        Timer::after(POLL_INTERVAL).await;
-
-        // Read current states (active high - adjust polarity as needed)
-        let control_raw = if polarity&1==0 {control_pin.is_low()}else{control_pin.is_high()};
-        let obstacle_raw = if polarity&2==0 {obstacle_pin.is_low()}else{obstacle_pin.is_high()};
-
-        // Update control input debouncer
-        if let Some(edge) = control_debouncer.update(control_raw) {
-            if edge {
-                // Rising edge on control input = pulse detected
-                println!("GPI: Control pulse detected");
-                GPI_CHANNEL.send(GpiEvent::ControlPulse).await;
-            }
-            // Falling edge is ignored for control input
-        }
+    let mut control = DebouncedInput::new(control_pin, InputLevels::CONTROL, polarity, config.control_debounce)
+        .with_current_level();
+    // An obstacle already present at start is reported; detection has its own, shorter debounce
+    let mut obstacle = DebouncedInput::new(obstacle_pin, InputLevels::OBSTACLE, polarity, config.obstacle_debounce);
+    obstacle.debounce_on = config.obstacle_detect_debounce;
+    let mut pedestrian = pedestrian_pin.map(|pin| {
+        DebouncedInput::new(pin, InputLevels::PEDESTRIAN, polarity, config.pedestrian_debounce).with_current_level()
+    });
+    // Start inactive so a switch that is already active reports the door position
+    let mut limits = limit_pins.map(|pins| {
+        let mut bits = InputLevels::LIMITS.into_iter();
+        pins.map(|pin| DebouncedInput::new(pin, bits.next().unwrap(), polarity, config.limit_debounce))
+    });
 
-        // Update obstacle input debouncer
-        if let Some(edge) = obstacle_debouncer.update(obstacle_raw) {
-            if edge {
-                println!("GPI: Obstacle detected");
-                GPI_CHANNEL.send(GpiEvent::ObstacleDetected).await;
-                last_obstacle_report = Some(Instant::now());
-            } else {
-                println!("GPI: Obstacle cleared");
-                GPI_CHANNEL.send(GpiEvent::ObstacleCleared).await;
-                last_obstacle_report = None;
-            }
+    let pedestrian_task = async {
+        match pedestrian.as_mut() {
+            Some(input) => watch_pulses(input, GpiEventKind::PedestrianPulse).await,
+            None => pending().await,
         }
-
-        if obstacle_raw {
-            let now = Instant::now();
-            let should_report = match last_obstacle_report {
-                None => true,
-                Some(last) => now.duration_since(last) >= OBSTACLE_REPEAT,
-            };
-
-            if should_report {
-                println!("GPI: Obstacle detected");
-                GPI_CHANNEL.send(GpiEvent::ObstacleDetected).await;
-                last_obstacle_report = Some(now);
+    };
+    let limits_task = async {
+        match limits.as_mut() {
+            Some([left_open, left_close, right_open, right_close]) => {
+                join4(
+                    watch_pulses(left_open, LIMIT_EVENTS[0]),
+                    watch_pulses(left_close, LIMIT_EVENTS[1]),
+                    watch_pulses(right_open, LIMIT_EVENTS[2]),
+                    watch_pulses(right_close, LIMIT_EVENTS[3]),
+                )
+                .await;
             }
-        } else {
-            last_obstacle_report = None;
+            None => pending().await,
         }
-    }
+    };
+    join4(
+        watch_control(&mut control, config.long_press, config.click_gap),
+        watch_obstacle(&mut obstacle),
+        pedestrian_task,
+        limits_task,
+    )
+    .await;
+    unreachable!("input watchers run forever")
+}
+
+/// Current debounced input levels
+pub fn input_levels() -> InputLevels {
+    INPUT_LEVELS.lock(|levels| levels.get())
 }
 
 /// Check if obstacle is currently detected (for synchronous checking during close sequence)
-/// Note: This is a snapshot and should be used with appropriate synchronization
+/// The level may already be accepted while its event is still queued for the FSM
 pub fn is_obstacle_active() -> bool {
-    // This would need to be implemented with a shared state if synchronous access is needed
-    // For the async approach, we rely on events through the channel
-    false
+    input_levels().is_active(InputLevels::OBSTACLE)
 }
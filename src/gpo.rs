@@ -1,145 +1,315 @@
 //! GPO (General Purpose Output) module
 //!
 //! Controls the output pins through a channel-based interface.
-//! Handles the signal lamp blinking logic internally.
+//! Handles the signal lamp patterns internally: the loop sleeps until the next
+//! lamp edge, relay release or command, so pattern timing is exact to the tick.
+//!
+//! In pulse mode a `GpoCommand::Pulse` closes the relay configured for that
+//! command for the pulse width, as a dry contact for a third-party controller.
+//!
+//! The open and close relays of a door are interlocked: they are never on
+//! together, and a direction change keeps both off for the configured dead
+//! time. Requests that would break the interlock are refused and reported to
+//! the FSM through `RELAY_FAULT_SIGNAL`.
 
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
-use embassy_time::{Duration};
-use esp_hal::gpio::{Level, Output};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, signal::Signal};
+use embassy_time::{with_deadline, Duration, Instant};
+use embedded_hal::digital::{OutputPin, PinState};
+#[cfg(feature = "esp")]
+use esp_hal::gpio::Output;
 
-use crate::types::{Door, GpoCommand, LampState};
-use esp_println::println;
+use crate::types::{
+    Door, DoorMovement, FaultCode, GpoCommand, GpoConfig, LampState, LampTimings, PulseCommand, PulseConfig,
+    RelayOutput,
+};
+use crate::println;
 
 /// Channel for sending commands to the GPO task
 pub static GPO_CHANNEL: Channel<CriticalSectionRawMutex, GpoCommand, 8> = Channel::new();
 
+/// Output polarity bits (set = active low): lamp, then the open/close relay
+/// pairs of the left and right door
+pub const POLARITY_LAMP: u32 = 1 << 0;
+pub const POLARITY_LEFT_OPEN: u32 = 1 << 1;
+pub const POLARITY_LEFT_CLOSE: u32 = 1 << 2;
+pub const POLARITY_RIGHT_OPEN: u32 = 1 << 3;
+pub const POLARITY_RIGHT_CLOSE: u32 = 1 << 4;
+
+/// Interlock violations, the FSM enters the Fault state on each one
+pub static RELAY_FAULT_SIGNAL: Signal<CriticalSectionRawMutex, FaultCode> = Signal::new();
+
+/// Relay pair of one door
+struct DoorRelays {
+    /// Direction requested by the FSM
+    requested: DoorMovement,
+    /// Direction currently driven, lags `requested` during the dead time
+    driven: DoorMovement,
+    /// Last direction driven and when its relay went off
+    last_driven: DoorMovement,
+    off_since: Instant,
+}
+
+impl DoorRelays {
+    const fn new() -> Self {
+        Self {
+            requested: DoorMovement::Stopped,
+            driven: DoorMovement::Stopped,
+            last_driven: DoorMovement::Stopped,
+            off_since: Instant::from_ticks(0),
+        }
+    }
+
+    /// Switch the relay for `movement` on or off
+    /// Returns false if that would energize both relays, the door is stopped instead
+    fn request(&mut self, movement: DoorMovement, active: bool) -> bool {
+        if !active {
+            if self.requested == movement {
+                self.requested = DoorMovement::Stopped;
+            }
+            true
+        } else if self.requested == DoorMovement::Stopped || self.requested == movement {
+            self.requested = movement;
+            true
+        } else {
+            self.requested = DoorMovement::Stopped;
+            false
+        }
+    }
+
+    /// Follow the requested direction, holding a reversal back for `dead_time`
+    /// Returns when the held back direction may start, if any
+    fn update(&mut self, now: Instant, dead_time: Duration) -> Option<Instant> {
+        if self.driven != self.requested && self.driven != DoorMovement::Stopped {
+            self.last_driven = self.driven;
+            self.off_since = now;
+            self.driven = DoorMovement::Stopped;
+        }
+        if self.driven == DoorMovement::Stopped && self.requested != DoorMovement::Stopped {
+            let ready_at = self.off_since + dead_time;
+            if self.requested != self.last_driven && now < ready_at {
+                return Some(ready_at);
+            }
+            self.driven = self.requested;
+        }
+        None
+    }
+}
+
 /// Internal state for the GPO module
 struct GpoState {
-    left_open: bool,
-    left_close: bool,
-    right_open: bool,
-    right_close: bool,
+    left: DoorRelays,
+    right: DoorRelays,
+    /// Running pulse and when it ends (None until its relay is driven)
+    pulse: Option<(RelayOutput, Option<Instant>)>,
     lamp_state: LampState,
+    /// When `lamp_state` last changed, flash sequences start from here
+    lamp_since: Instant,
 }
 
 impl GpoState {
     const fn new() -> Self {
         Self {
-            left_open: false,
-            left_close: false,
-            right_open: false,
-            right_close: false,
+            left: DoorRelays::new(),
+            right: DoorRelays::new(),
+            pulse: None,
             lamp_state: LampState::Off,
+            lamp_since: Instant::from_ticks(0),
+        }
+    }
+
+    fn door(&mut self, door: Door) -> &mut DoorRelays {
+        match door {
+            Door::Left => &mut self.left,
+            Door::Right => &mut self.right,
         }
     }
 
-    fn apply_command(&mut self, cmd: GpoCommand) {
+    fn apply_command(&mut self, cmd: GpoCommand, pulse: &PulseConfig) {
         match cmd {
-            GpoCommand::SetDoorOpen { door, active } => match door {
-                Door::Left => self.left_open = active,
-                Door::Right => self.right_open = active,
-            },
-            GpoCommand::SetDoorClose { door, active } => match door {
-                Door::Left => self.left_close = active,
-                Door::Right => self.right_close = active,
-            },
-            GpoCommand::SetLamp(state) => self.lamp_state = state,
+            GpoCommand::SetDoorOpen { door, active } => self.request(door, DoorMovement::Opening, active),
+            GpoCommand::SetDoorClose { door, active } => self.request(door, DoorMovement::Closing, active),
+            GpoCommand::SetLamp(state) => {
+                if state != self.lamp_state {
+                    self.lamp_state = state;
+                    self.lamp_since = Instant::now();
+                }
+            }
+            GpoCommand::Pulse(command) => self.start_pulse(command, pulse),
+        }
+    }
+
+    /// Switch a relay, reporting interlock violations to the FSM
+    fn request(&mut self, door: Door, movement: DoorMovement, active: bool) {
+        if !self.door(door).request(movement, active) {
+            println!("GPO interlock: {:?} door open and close requested together", door);
+            RELAY_FAULT_SIGNAL.signal(FaultCode::RelayInterlock);
+        }
+    }
+
+    /// Start a pulse, cutting a running one short
+    fn start_pulse(&mut self, command: PulseCommand, pulse: &PulseConfig) {
+        if let Some((relay, _)) = self.pulse.take() {
+            self.request(relay.door(), relay.movement(), false);
+        }
+        match pulse.relay(command) {
+            Some(relay) => {
+                self.request(relay.door(), relay.movement(), true);
+                self.pulse = Some((relay, None));
+            }
+            None => println!("GPO: no relay configured for {:?} pulses", command),
+        }
+    }
+
+    /// Drive the door relays for the requests and the running pulse
+    /// Returns when the outputs next change on their own (dead time or pulse end)
+    fn update_relays(&mut self, now: Instant, config: &GpoConfig) -> Option<Instant> {
+        if let Some((relay, Some(end))) = self.pulse {
+            if now >= end {
+                self.request(relay.door(), relay.movement(), false);
+            }
         }
+        let left = self.left.update(now, config.relay_dead_time);
+        let right = self.right.update(now, config.relay_dead_time);
+
+        // A pulse is timed from when its relay is driven, and dropped once released
+        let pulse_end = match self.pulse {
+            Some((relay, end)) => {
+                let relays = self.door(relay.door());
+                if relays.requested != relay.movement() {
+                    self.pulse = None;
+                    None
+                } else if end.is_none() && relays.driven == relay.movement() {
+                    let end = now + config.pulse.width;
+                    self.pulse = Some((relay, Some(end)));
+                    Some(end)
+                } else {
+                    end
+                }
+            }
+            None => None,
+        };
+        [left, right, pulse_end].into_iter().flatten().min()
+    }
+
+    /// Lamp level for the current lamp state, and when it next changes (None: held)
+    fn lamp_phase(&self, timings: &LampTimings, now: Instant) -> (bool, Option<Instant>) {
+        let elapsed = now.saturating_duration_since(self.lamp_since);
+        let (on, remaining) = match self.lamp_state {
+            LampState::Off => (false, None),
+            LampState::Steady => (true, None),
+            LampState::Blinking => blink_phase(timings.blink_on, timings.blink_off, elapsed),
+            LampState::Prestart => blink_phase(timings.prestart_on, timings.prestart_off, elapsed),
+            LampState::Fault(code) => fault_phase(code, timings, elapsed),
+        };
+        (on, remaining.map(|r| now + r))
     }
 }
 
+/// Position `elapsed` into an on/off cycle: level and time left in that phase
+fn blink_phase(on: Duration, off: Duration, elapsed: Duration) -> (bool, Option<Duration>) {
+    if off.as_ticks() == 0 || on.as_ticks() == 0 {
+        return (on.as_ticks() != 0, None);
+    }
+    let t = elapsed.as_ticks() % (on + off).as_ticks();
+    if t < on.as_ticks() {
+        (true, Some(on - Duration::from_ticks(t)))
+    } else {
+        (false, Some(on + off - Duration::from_ticks(t)))
+    }
+}
+
+/// Position `elapsed` into a fault code sequence: `flashes` on/off flashes,
+/// the last off phase extended by the pause
+fn fault_phase(code: FaultCode, timings: &LampTimings, elapsed: Duration) -> (bool, Option<Duration>) {
+    let flash = timings.fault_flash.as_ticks().max(1);
+    let flashes_end = code.flashes() as u64 * flash * 2;
+    let period = flashes_end + timings.fault_pause.as_ticks();
+    let t = elapsed.as_ticks() % period;
+    // The last flash ends at flashes_end - flash, the lamp is then off until the period ends
+    let (on, next) = if t < flashes_end - flash {
+        ((t / flash).is_multiple_of(2), (t / flash + 1) * flash)
+    } else {
+        (false, period)
+    };
+    (on, Some(Duration::from_ticks(next - t)))
+}
+
 /// GPO task - controls all output pins
 ///
 /// Receives commands through GPO_CHANNEL and updates output pins accordingly.
-/// The lamp blinking is handled with a timer-based approach.
+/// The lamp patterns are timed from `config.lamp`.
+#[cfg(feature = "esp")]
 #[embassy_executor::task]
 pub async fn gpo_task(
-    mut lamp_pin: Output<'static>,
-    mut left_open_pin: Output<'static>,
-    mut left_close_pin: Output<'static>,
-    mut right_open_pin: Output<'static>,
-    mut right_close_pin: Output<'static>,
-    polarity: u32,
+    lamp_pin: Output<'static>,
+    left_open_pin: Output<'static>,
+    left_close_pin: Output<'static>,
+    right_open_pin: Output<'static>,
+    right_close_pin: Output<'static>,
+    config: GpoConfig,
 ) {
-    println!("GPO task started polarity {}",polarity & 255);
+    run_gpo(lamp_pin, left_open_pin, left_close_pin, right_open_pin, right_close_pin, config).await
+}
 
-    let mut state = GpoState::new();
-    // let mut lamp_on = false;
+/// Drive an output, pin errors are ignored (GPIO writes are infallible on the target)
+/// `active_low` is the output's polarity bit
+fn set_pin<P: OutputPin>(pin: &mut P, active: bool, active_low: bool) {
+    let _ = pin.set_state(PinState::from(active ^ active_low));
+}
 
-    // Lamp timing: 1/2 Hz = 2 second period, 75% duty = 1.5s on, 0.5s off
-    // const LAMP_ON_TIME: Duration = Duration::from_millis(1500);
-    // const LAMP_OFF_TIME: Duration = Duration::from_millis(500);
-    const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Drive the relay pair of a door, switching the released relay off first
+/// `polarity` holds the open relay bit, then the close relay bit
+fn set_door_pins<P: OutputPin>(open_pin: &mut P, close_pin: &mut P, driven: DoorMovement, polarity: u32) {
+    let (open_low, close_low) = (polarity & 1 != 0, polarity & 2 != 0);
+    match driven {
+        DoorMovement::Opening => {
+            set_pin(close_pin, false, close_low);
+            set_pin(open_pin, true, open_low);
+        }
+        DoorMovement::Closing => {
+            set_pin(open_pin, false, open_low);
+            set_pin(close_pin, true, close_low);
+        }
+        DoorMovement::Stopped => {
+            set_pin(open_pin, false, open_low);
+            set_pin(close_pin, false, close_low);
+        }
+    }
+}
 
-    // let mut lamp_timer_ms: u64 = 0;
+/// GPO loop, generic over the output pins so it can also run in the host simulation
+pub async fn run_gpo<P: OutputPin>(
+    mut lamp_pin: P,
+    mut left_open_pin: P,
+    mut left_close_pin: P,
+    mut right_open_pin: P,
+    mut right_close_pin: P,
+    config: GpoConfig,
+) -> ! {
+    let polarity = config.polarity;
+    println!("GPO task started polarity {} dead time {} ms",polarity & 255, config.relay_dead_time.as_millis());
+
+    let mut state = GpoState::new();
 
     loop {
-        // Try to receive a command (non-blocking with timeout)
-        match embassy_time::with_timeout(POLL_INTERVAL, GPO_CHANNEL.receive()).await {
-            Ok(cmd) => {
-                println!("GPO received command: {:?}", cmd);
-                state.apply_command(cmd);
-            }
-            Err(_) => {
-                // Timeout - just continue with lamp logic
-            }
-        }
+        // Update outputs, all inactive on the first pass
+        let now = Instant::now();
+        let relay_change = state.update_relays(now, &config);
+        set_door_pins(&mut left_open_pin, &mut left_close_pin, state.left.driven, polarity >> 1);
+        set_door_pins(&mut right_open_pin, &mut right_close_pin, state.right.driven, polarity >> 3);
 
-        // Update door outputs (active high - adjust polarity as needed)
-        left_open_pin.set_level(if state.left_open {
-            Level::High
-        } else {
-            Level::Low
-        });
-        left_close_pin.set_level(if state.left_close {
-            Level::High
-        } else {
-            Level::Low
-        });
-        right_open_pin.set_level(if state.right_open {
-            Level::High
-        } else {
-            Level::Low
-        });
-        right_close_pin.set_level(if state.right_close {
-            Level::High
-        } else {
-            Level::Low
-        });
-
-        // Handle lamp blinking
-        match state.lamp_state {
-            LampState::Off => {
-                if polarity&1 != 0 {
-                    lamp_pin.set_high();
-                }else{
-                    lamp_pin.set_low();
-                }
-                // lamp_on = false;
-                // lamp_timer_ms = 0;
-            }
-            LampState::Blinking => {
-                if polarity&1 == 0 {
-                    lamp_pin.set_high();
-                }else{
-                    lamp_pin.set_low();
-                }
-                // lamp_timer_ms += POLL_INTERVAL.as_millis();
-
-                // if lamp_on {
-                //     if lamp_timer_ms >= LAMP_ON_TIME.as_millis() {
-                //         lamp_on = false;
-                //         lamp_timer_ms = 0;
-                //     }
-                // } else {
-                //     if lamp_timer_ms >= LAMP_OFF_TIME.as_millis() {
-                //         lamp_on = true;
-                //         lamp_timer_ms = 0;
-                //     }
-                // }
-
-                // lamp_pin.set_level(if lamp_on^(polarity&1!=0) { Level::High } else { Level::Low });
-            }
+        let (lamp_on, lamp_change) = state.lamp_phase(&config.lamp, now);
+        set_pin(&mut lamp_pin, lamp_on, polarity & POLARITY_LAMP != 0);
+
+        // Wait for a command, or until the next lamp edge, reversal start or pulse end
+        let wake = [relay_change, lamp_change].into_iter().flatten().min();
+        let received = match wake {
+            Some(at) => with_deadline(at, GPO_CHANNEL.receive()).await.ok(),
+            None => Some(GPO_CHANNEL.receive().await),
+        };
+        if let Some(cmd) = received {
+            println!("GPO received command: {:?}", cmd);
+            state.apply_command(cmd, &config.pulse);
         }
     }
 }
@@ -181,10 +351,23 @@ pub mod commands {
         send_gpo_command(GpoCommand::SetLamp(LampState::Blinking)).await;
     }
 
+    pub async fn lamp_prestart() {
+        send_gpo_command(GpoCommand::SetLamp(LampState::Prestart)).await;
+    }
+
     pub async fn lamp_off() {
         send_gpo_command(GpoCommand::SetLamp(LampState::Off)).await;
     }
 
+    /// Pulse the relay wired to a third-party controller input
+    pub async fn pulse(command: PulseCommand) {
+        send_gpo_command(GpoCommand::Pulse(command)).await;
+    }
+
+    pub async fn lamp_fault(code: FaultCode) {
+        send_gpo_command(GpoCommand::SetLamp(LampState::Fault(code))).await;
+    }
+
     /// Stop all door movements
     pub async fn stop_all_doors() {
         send_gpo_command(GpoCommand::SetDoorOpen {
@@ -1,111 +1,325 @@
+//! Key store
+//!
+//! Keys are held in memory and persisted as one `sequential_storage::map`
+//! record per key, under a stable record ID. Adding a key writes its record,
+//! deleting one writes an empty tombstone record for its ID, so every edit is a
+//! single flash write and a power loss can at most lose the edit in progress.
+//!
+//! A key can be limited to a validity window (Unix seconds), to a weekly
+//! schedule profile (`schedule` module) and to a number of uses. Records are
+//! versioned: v1 is the bare 33 byte key of earlier firmware and loads as an
+//! unlimited key, v2 added the window, every record written now is v3
+//! (`RECORD_V3`) with the schedule ID. The remaining uses are not part of the
+//! record: they live in their own range (`USES_RANGE`) as one counter per
+//! use-limited key, so a counted use only writes a counter.
+//!
+//! Each key also has a `KeyInfo` (label, creation time, creator) for admin
+//! apps. It is only needed on management requests, so it stays on flash, in its
+//! own range under the key's record ID, and is not held in RAM.
+
+use crate::schedule::ScheduleStore;
 use embedded_storage_async::nor_flash::NorFlash;
 use heapless::Vec;
 use sequential_storage::cache::NoCache;
 use sequential_storage::map;
+use sha2::{Digest, Sha256};
 
 /// Maximum number of keys that can be stored
 pub const STORE_KEYS: usize = 1024;
 
 /// Flash storage range for keys
-/// Each key needs ~48 bytes (33 bytes data + sequential_storage overhead)
-/// For 1024 keys: 1024 * 48 = ~50KB, using 64KB (0x10000) for safety margin
+/// Each key needs 56 bytes: 8 byte item header, 2 byte ID and 43 byte v3 record, padded to 4
+/// For 1024 keys: 1024 * 56 = 56KB of the 60KB left with one page kept free for
+/// garbage collection, the rest is headroom so deletes and edits of a full store fit
 /// Range: 0x3E0000..0x3F0000 (64KB)
-/// Note: Settings use 0x3DF000..0x3E0000 (4KB before this range)
+/// Note: Settings use 0x3DE000..0x3E0000 (8KB before this range)
 const FLASH_RANGE: core::ops::Range<u32> = 0x3E0000..0x3F0000;
 
-/// Key for storing the key count in the map (u16 to support >255 keys)
+/// Flash storage range for the remaining uses of use-limited keys, one 16 byte record each
+/// Every counted use writes another record
+/// 32KB directly before the key info range (0x3C2000..0x3D2000)
+const USES_RANGE: core::ops::Range<u32> = 0x3BA000..0x3C2000;
+
+/// Flash storage range for the key infos, one ~48 byte record per key
+/// 64KB directly before the schedule range (0x3D2000..0x3D4000)
+const INFO_RANGE: core::ops::Range<u32> = 0x3C2000..0x3D2000;
+
+/// Key count of the old layout, which rewrote the count and every key on each edit
+/// Keys past the count were stale; `MIGRATED` once they have been tombstoned
 const KEY_COUNT_ID: u16 = 0;
-/// Starting ID for actual keys
+const MIGRATED: u16 = u16::MAX;
+/// Record ID of the first key slot
 const KEY_START_ID: u16 = 1;
 
 /// Mask for key type flags (2 LSB bits)
 const KEY_FLAGS_MASK: u8 = 0x03;
 
+/// v1 record: the key only
+const RECORD_V1_LEN: usize = 33;
+/// v2 record: `RECORD_V2`, the key, not before and not after (u32 LE each)
+const RECORD_V2: u8 = 2;
+const RECORD_V2_LEN: usize = 1 + 33 + 8;
+/// v3 record: `RECORD_V3`, the v2 fields, schedule ID
+const RECORD_V3: u8 = 3;
+const RECORD_V3_LEN: usize = RECORD_V2_LEN + 1;
+
+/// Schedule ID of a key without a schedule
+pub const NO_SCHEDULE: u8 = 0;
+/// Remaining uses of a key that is not use-limited
+pub const UNLIMITED_USES: u32 = u32::MAX;
+
+/// Label length, UTF-8 padded with zero bytes
+pub const LABEL_LEN: usize = 24;
+/// Serialized `KeyInfo` length (also the size of the `management_key_info` characteristic)
+pub const KEY_INFO_LEN: usize = LABEL_LEN + 4 + 8;
+
+/// Admin metadata of a key, all zeros for keys added before it was recorded
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct KeyInfo {
+    pub label: [u8; LABEL_LEN],
+    /// Unix seconds when the key was added, 0 if the clock was not set
+    pub created: u32,
+    /// `fingerprint` of the key that added it, zeros if added in programming mode
+    pub creator: [u8; 8],
+}
+
+impl KeyInfo {
+    /// Info of a key being added now by `creator`
+    pub fn new(created: Option<u64>, creator: Option<&[u8; 33]>) -> Self {
+        Self {
+            label: [0; LABEL_LEN],
+            created: created.unwrap_or(0) as u32,
+            creator: creator.map(fingerprint).unwrap_or_default(),
+        }
+    }
+
+    /// Label text, None if it is not valid UTF-8
+    pub fn label(&self) -> Option<&str> {
+        let len = self.label.iter().position(|&b| b == 0).unwrap_or(LABEL_LEN);
+        core::str::from_utf8(&self.label[..len]).ok()
+    }
+
+    /// Serialize to the wire/flash format: label, created (u32 LE), creator
+    pub fn to_bytes(&self) -> [u8; KEY_INFO_LEN] {
+        let mut out = [0u8; KEY_INFO_LEN];
+        out[..LABEL_LEN].copy_from_slice(&self.label);
+        out[LABEL_LEN..LABEL_LEN + 4].copy_from_slice(&self.created.to_le_bytes());
+        out[LABEL_LEN + 4..].copy_from_slice(&self.creator);
+        out
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != KEY_INFO_LEN {
+            return None;
+        }
+        Some(Self {
+            label: data[..LABEL_LEN].try_into().ok()?,
+            created: u32::from_le_bytes(data[LABEL_LEN..LABEL_LEN + 4].try_into().ok()?),
+            creator: data[LABEL_LEN + 4..].try_into().ok()?,
+        })
+    }
+}
+
+/// Short key fingerprint: the first 8 bytes of SHA-256 over the key type and key bytes
+/// Permission bits are left out, so it does not change with them
+pub fn fingerprint(key: &[u8; 33]) -> [u8; 8] {
+    let mut hasher = Sha256::new();
+    hasher.update([key[0] & KEY_FLAGS_MASK]);
+    hasher.update(&key[1..]);
+    let digest = hasher.finalize();
+    let mut out = [0u8; 8];
+    out.copy_from_slice(&digest[..8]);
+    out
+}
+
+/// Why a presented key is not accepted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessDenied {
+    UnknownKey,
+    /// Outside the validity window
+    NotValid,
+    /// Outside the schedule, or the schedule profile was deleted
+    OutsideSchedule,
+    /// The key is limited but the clock is not set
+    TimeUnsynced,
+    /// No uses left
+    UsedUp,
+}
+
+/// Time window a key is accepted in, Unix seconds; 0 leaves that end open
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Validity {
+    pub not_before: u32,
+    pub not_after: u32,
+}
+
+impl Validity {
+    /// No window, the key is always accepted
+    pub const ALWAYS: Self = Self { not_before: 0, not_after: 0 };
+
+    pub fn is_limited(&self) -> bool {
+        *self != Self::ALWAYS
+    }
+
+    /// Check the window at `now` (Unix seconds, None if the clock is not set)
+    /// A limited key is refused while the time is unknown
+    pub fn allows(&self, now: Option<u64>) -> bool {
+        if !self.is_limited() {
+            return true;
+        }
+        let Some(now) = now else {
+            return false;
+        };
+        (self.not_before == 0 || now >= self.not_before as u64) && (self.not_after == 0 || now <= self.not_after as u64)
+    }
+
+    /// Encoding used by the `management_validity` characteristic and the v2 record
+    pub fn to_bytes(&self) -> [u8; 8] {
+        let mut out = [0u8; 8];
+        out[..4].copy_from_slice(&self.not_before.to_le_bytes());
+        out[4..].copy_from_slice(&self.not_after.to_le_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8; 8]) -> Self {
+        Self {
+            not_before: u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            not_after: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        }
+    }
+}
+
+/// A key, its limits and the ID of its flash record
+#[derive(Clone, Copy)]
+struct StoredKey {
+    id: u16,
+    key: [u8; 33],
+    validity: Validity,
+    schedule: u8,
+    uses: u32,
+}
+
+impl StoredKey {
+    /// Key without limits
+    fn new(id: u16, key: [u8; 33]) -> Self {
+        Self { id, key, validity: Validity::ALWAYS, schedule: NO_SCHEDULE, uses: UNLIMITED_USES }
+    }
+
+    /// Decode a key record of any version, None for a tombstone or an unknown layout
+    /// Fields a version does not have keep their unlimited value
+    fn decode(id: u16, value: &[u8]) -> Option<Self> {
+        match (value.len(), value.first()) {
+            (RECORD_V1_LEN, _) => return Some(Self::new(id, value.try_into().ok()?)),
+            (RECORD_V2_LEN, Some(&RECORD_V2)) | (RECORD_V3_LEN, Some(&RECORD_V3)) => {}
+            _ => return None,
+        }
+        let mut stored = Self::new(id, value[1..34].try_into().ok()?);
+        stored.validity = Validity::from_bytes(value[34..42].try_into().ok()?);
+        if let Some(&schedule) = value.get(42) {
+            stored.schedule = schedule;
+        }
+        Some(stored)
+    }
+
+    /// Encode as a v3 record, the remaining uses are stored as a counter
+    fn encode(&self) -> [u8; RECORD_V3_LEN] {
+        let mut out = [0u8; RECORD_V3_LEN];
+        out[0] = RECORD_V3;
+        out[1..34].copy_from_slice(&self.key);
+        out[34..42].copy_from_slice(&self.validity.to_bytes());
+        out[42] = self.schedule;
+        out
+    }
+}
+
 /// Key storage manager that holds keys in memory and persists to flash
+/// Keys are kept in record ID order, so `get` indexes are stable across restarts
 pub struct KeyStore {
-    keys: Vec<[u8; 33], STORE_KEYS>,
+    keys: Vec<StoredKey, STORE_KEYS>,
 }
 
 impl KeyStore {
     /// Create a new KeyStore and load existing keys from flash
     /// Returns the KeyStore and gives back flash ownership
     pub async fn new<S: NorFlash>(mut flash: S) -> (Self, S) {
-        let keys = Self::load_from_flash(&mut flash).await;
-        (Self { keys }, flash)
+        let mut store = Self { keys: Vec::new() };
+        // Keys read before an error are kept
+        let _ = store.load_from_flash(&mut flash).await;
+        (store, flash)
     }
 
-    /// Load keys from flash storage
-    async fn load_from_flash<S: NorFlash>(flash: &mut S) -> Vec<[u8; 33], STORE_KEYS> {
-        let mut keys: Vec<[u8; 33], STORE_KEYS> = Vec::new();
+    /// Load keys from flash storage, converting a store in the old layout
+    async fn load_from_flash<S: NorFlash>(&mut self, flash: &mut S) -> Result<(), sequential_storage::Error<S::Error>> {
         let mut cache = NoCache::new();
         let mut buf = [0u8; 64];
+        let mut legacy_count = None;
 
-        let count: u16 = match map::fetch_item::<u16, u16, _>(
-            flash,
-            FLASH_RANGE,
-            &mut cache,
-            &mut buf,
-            &KEY_COUNT_ID,
-        )
-        .await
         {
-            Ok(Some(c)) => c,
-            Ok(None) => 0,
-            Err(_) => 0,
-        };
+            let mut records = map::fetch_all_items::<u16, _, _>(flash, FLASH_RANGE, &mut cache, &mut buf).await?;
+            // Records come oldest first, a later record for an ID replaces the earlier one
+            while let Some((id, value)) = records.next::<&[u8]>(&mut buf).await? {
+                if id == KEY_COUNT_ID {
+                    legacy_count = value.try_into().ok().map(u16::from_le_bytes);
+                } else {
+                    self.apply(id, value);
+                }
+            }
+        }
 
-        for i in 0..count {
-            let key_id = KEY_START_ID.wrapping_add(i);
-            match map::fetch_item::<u16, [u8; 33], _>(
-                flash,
-                FLASH_RANGE,
-                &mut cache,
-                &mut buf,
-                &key_id,
-            )
-            .await
-            {
-                Ok(Some(key)) => {
-                    let _ = keys.push(key);
+        {
+            let mut counters = map::fetch_all_items::<u16, _, _>(flash, USES_RANGE, &mut cache, &mut buf).await?;
+            while let Some((id, value)) = counters.next::<&[u8]>(&mut buf).await? {
+                // The counter of a deleted key is a tombstone (empty value), as for the keys
+                let pos = self.keys.partition_point(|k| k.id < id);
+                if let Some(stored) = self.keys.get_mut(pos).filter(|k| k.id == id) {
+                    stored.uses = value.try_into().map(u32::from_le_bytes).unwrap_or(UNLIMITED_USES);
                 }
-                Ok(None) => {}
-                Err(_) => {}
             }
         }
 
-        keys
-    }
+        if let Some(count) = legacy_count.filter(|&count| count != MIGRATED) {
+            // Deleting in the old layout shifted the keys down and left the last record behind
+            while let Some(stale) = self.keys.last().map(|k| k.id).filter(|&id| id - KEY_START_ID >= count) {
+                store_record(flash, stale, &[]).await?;
+                self.keys.pop();
+            }
+            map::store_item::<u16, u16, _>(flash, FLASH_RANGE, &mut cache, &mut buf, &KEY_COUNT_ID, &MIGRATED).await?;
+        }
 
-    /// Save all keys to flash storage
-    async fn save_to_flash<S: NorFlash>(&self, flash: &mut S) -> Result<(), sequential_storage::Error<S::Error>> {
-        let mut cache = NoCache::new();
-        let mut buf = [0u8; 64];
+        Ok(())
+    }
 
-        let count = self.keys.len() as u16;
-        map::store_item::<u16, u16, _>(
-            flash,
-            FLASH_RANGE,
-            &mut cache,
-            &mut buf,
-            &KEY_COUNT_ID,
-            &count,
-        )
-        .await?;
-
-        for (i, key) in self.keys.iter().enumerate() {
-            let key_id = KEY_START_ID.wrapping_add(i as u16);
-            map::store_item::<u16, [u8; 33], _>(
-                flash,
-                FLASH_RANGE,
-                &mut cache,
-                &mut buf,
-                &key_id,
-                key,
-            )
-            .await?;
+    /// Apply a key record read from flash: a key, or a tombstone (empty value)
+    fn apply(&mut self, id: u16, value: &[u8]) {
+        let pos = self.keys.partition_point(|k| k.id < id);
+        let exists = self.keys.get(pos).is_some_and(|k| k.id == id);
+        match StoredKey::decode(id, value) {
+            Some(stored) if exists => self.keys[pos] = stored,
+            Some(stored) => {
+                let _ = self.keys.insert(pos, stored);
+            }
+            None if exists => {
+                self.keys.remove(pos);
+            }
+            None => {}
         }
+    }
 
-        Ok(())
+    /// Stored entry matching `key`
+    fn find(&self, key: &[u8; 33]) -> Option<usize> {
+        self.keys.iter().position(|stored| Self::keys_match(&stored.key, key))
+    }
+
+    /// Lowest record ID not used by a stored key, tombstoned IDs are reused
+    fn free_id(&self) -> u16 {
+        let mut id = KEY_START_ID;
+        for stored in &self.keys {
+            if stored.id != id {
+                break;
+            }
+            id += 1;
+        }
+        id
     }
 
     /// Compare two keys: matches if 2 LSB bits of first byte and bytes 1..33 are equal
@@ -113,23 +327,33 @@ impl KeyStore {
         (stored[0] & KEY_FLAGS_MASK) == (provided[0] & KEY_FLAGS_MASK) && stored[1..] == provided[1..]
     }
 
-    /// Add a key to the store and persist to flash
+    /// Add a key with its info to the store and persist to flash
     /// Returns Ok(true) if added, Ok(false) if already exists or store is full
-    pub async fn add<S: NorFlash>(&mut self, flash: &mut S, key: [u8; 33]) -> Result<bool, sequential_storage::Error<S::Error>> {
+    pub async fn add<S: NorFlash>(
+        &mut self,
+        flash: &mut S,
+        key: [u8; 33],
+        info: KeyInfo,
+    ) -> Result<bool, sequential_storage::Error<S::Error>> {
         // Check if key already exists
-        for stored in &self.keys {
-            if Self::keys_match(stored, &key) {
-                return Ok(false);
-            }
+        if self.find(&key).is_some() {
+            return Ok(false);
         }
-
-        // Add to in-memory store
-        if self.keys.push(key).is_err() {
-            return Ok(false); // Store is full
+        if self.keys.is_full() {
+            return Ok(false);
         }
 
-        // Persist to flash
-        self.save_to_flash(flash).await?;
+        // Only a key that made it to flash is usable
+        let stored = StoredKey::new(self.free_id(), key);
+        // A counter left by a deleted key that had this ID would limit the new key
+        if load_uses(flash, stored.id).await?.is_some() {
+            store_uses(flash, stored.id, &[]).await?;
+        }
+        // The info first: it replaces the one of a deleted key that had this ID
+        store_info(flash, stored.id, &info).await?;
+        store_record(flash, stored.id, &stored.encode()).await?;
+        let pos = self.keys.partition_point(|k| k.id < stored.id);
+        let _ = self.keys.insert(pos, stored);
         Ok(true)
     }
 
@@ -137,19 +361,122 @@ impl KeyStore {
     /// Compares using 2 LSB bits of first byte and bytes 1..33
     /// Returns Ok(true) if deleted, Ok(false) if not found
     pub async fn del<S: NorFlash>(&mut self, flash: &mut S, key: [u8; 33]) -> Result<bool, sequential_storage::Error<S::Error>> {
-        let mut found_idx = None;
+        match self.find(&key) {
+            Some(idx) => {
+                let stored = self.keys[idx];
+                store_record(flash, stored.id, &[]).await?;
+                self.keys.remove(idx);
+                // A counter left by a power loss here is dropped when the ID is reused
+                if stored.uses != UNLIMITED_USES {
+                    store_uses(flash, stored.id, &[]).await?;
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
 
-        for (i, stored) in self.keys.iter().enumerate() {
-            if Self::keys_match(stored, &key) {
-                found_idx = Some(i);
-                break;
+    /// Limit a stored key to a validity window (`Validity::ALWAYS` lifts the limit)
+    /// Returns Ok(true) if set, Ok(false) if not found
+    pub async fn set_validity<S: NorFlash>(
+        &mut self,
+        flash: &mut S,
+        key: [u8; 33],
+        validity: Validity,
+    ) -> Result<bool, sequential_storage::Error<S::Error>> {
+        self.update(flash, key, |stored| stored.validity = validity).await
+    }
+
+    /// Limit a stored key to schedule profile `schedule` (`NO_SCHEDULE` lifts the limit)
+    /// Returns Ok(true) if set, Ok(false) if not found
+    pub async fn set_schedule<S: NorFlash>(
+        &mut self,
+        flash: &mut S,
+        key: [u8; 33],
+        schedule: u8,
+    ) -> Result<bool, sequential_storage::Error<S::Error>> {
+        self.update(flash, key, |stored| stored.schedule = schedule).await
+    }
+
+    /// Limit a stored key to `uses` more uses (`UNLIMITED_USES` lifts the limit)
+    /// Returns Ok(true) if set, Ok(false) if not found
+    pub async fn set_uses<S: NorFlash>(
+        &mut self,
+        flash: &mut S,
+        key: [u8; 33],
+        uses: u32,
+    ) -> Result<bool, sequential_storage::Error<S::Error>> {
+        match self.find(&key) {
+            Some(idx) => {
+                store_uses(flash, self.keys[idx].id, &uses.to_le_bytes()).await?;
+                self.keys[idx].uses = uses;
+                Ok(true)
             }
+            None => Ok(false),
+        }
+    }
+
+    /// Count one use of a stored key, a key with no uses left stays stored but is refused
+    /// Returns the uses left, None if the key is not use-limited or not found
+    pub async fn record_use<S: NorFlash>(
+        &mut self,
+        flash: &mut S,
+        key: [u8; 33],
+    ) -> Result<Option<u32>, sequential_storage::Error<S::Error>> {
+        let Some(uses) = self.uses(&key).filter(|&uses| uses != UNLIMITED_USES) else {
+            return Ok(None);
+        };
+        let left = uses.saturating_sub(1);
+        self.set_uses(flash, key, left).await?;
+        Ok(Some(left))
+    }
+
+    /// Info of a stored key, None if not found
+    pub async fn info<S: NorFlash>(
+        &self,
+        flash: &mut S,
+        key: &[u8; 33],
+    ) -> Result<Option<KeyInfo>, sequential_storage::Error<S::Error>> {
+        match self.find(key) {
+            Some(idx) => Ok(Some(load_info(flash, self.keys[idx].id).await?)),
+            None => Ok(None),
         }
+    }
+
+    /// Label a stored key, the rest of its info is kept
+    /// Returns Ok(true) if set, Ok(false) if not found
+    pub async fn set_label<S: NorFlash>(
+        &mut self,
+        flash: &mut S,
+        key: [u8; 33],
+        label: [u8; LABEL_LEN],
+    ) -> Result<bool, sequential_storage::Error<S::Error>> {
+        let Some(idx) = self.find(&key) else {
+            return Ok(false);
+        };
+        let id = self.keys[idx].id;
+        let info = KeyInfo { label, ..load_info(flash, id).await? };
+        store_info(flash, id, &info).await?;
+        Ok(true)
+    }
 
-        match found_idx {
+    /// Rewrite the record of a stored key after `edit`, the key in RAM changes once it is on flash
+    /// The record does not carry the uses, a use-limited key gets its counter written first
+    async fn update<S: NorFlash>(
+        &mut self,
+        flash: &mut S,
+        key: [u8; 33],
+        edit: impl FnOnce(&mut StoredKey),
+    ) -> Result<bool, sequential_storage::Error<S::Error>> {
+        match self.find(&key) {
             Some(idx) => {
-                self.keys.remove(idx);
-                self.save_to_flash(flash).await?;
+                let mut stored = self.keys[idx];
+                edit(&mut stored);
+                if stored.uses != UNLIMITED_USES {
+                    store_uses(flash, stored.id, &stored.uses.to_le_bytes()).await?;
+                }
+                store_record(flash, stored.id, &stored.encode()).await?;
+                self.keys[idx] = stored;
                 Ok(true)
             }
             None => Ok(false),
@@ -159,13 +486,45 @@ impl KeyStore {
     /// Lookup a key in the store
     /// Compares using 2 LSB bits of first byte and bytes 1..33
     /// Returns the first byte (containing permissions in 6 MSB bits) if found, 0 if not found
+    /// The validity window is not checked, see `authorize`
     pub fn lookup(&self, key: &[u8; 33]) -> u8 {
-        for stored in &self.keys {
-            if Self::keys_match(stored, key) {
-                return stored[0];
+        self.find(key).map(|idx| self.keys[idx].key[0]).unwrap_or(0)
+    }
+
+    /// Lookup a key presented by a client at `now` (Unix seconds, None if the clock is not set)
+    /// Returns the first byte as `lookup` does, or why the key is not accepted now
+    pub fn authorize(&self, key: &[u8; 33], now: Option<u64>, schedules: &ScheduleStore) -> Result<u8, AccessDenied> {
+        let stored = self.find(key).map(|idx| &self.keys[idx]).ok_or(AccessDenied::UnknownKey)?;
+        if stored.uses == 0 {
+            return Err(AccessDenied::UsedUp);
+        }
+        if (stored.validity.is_limited() || stored.schedule != NO_SCHEDULE) && now.is_none() {
+            return Err(AccessDenied::TimeUnsynced);
+        }
+        if !stored.validity.allows(now) {
+            return Err(AccessDenied::NotValid);
+        }
+        if let Some(now) = now.filter(|_| stored.schedule != NO_SCHEDULE) {
+            if !schedules.allows(stored.schedule, now) {
+                return Err(AccessDenied::OutsideSchedule);
             }
         }
-        0
+        Ok(stored.key[0])
+    }
+
+    /// Validity window of a stored key, None if not found
+    pub fn validity(&self, key: &[u8; 33]) -> Option<Validity> {
+        self.find(key).map(|idx| self.keys[idx].validity)
+    }
+
+    /// Schedule profile ID of a stored key, None if not found
+    pub fn schedule(&self, key: &[u8; 33]) -> Option<u8> {
+        self.find(key).map(|idx| self.keys[idx].schedule)
+    }
+
+    /// Remaining uses of a stored key (`UNLIMITED_USES` if not use-limited), None if not found
+    pub fn uses(&self, key: &[u8; 33]) -> Option<u32> {
+        self.find(key).map(|idx| self.keys[idx].uses)
     }
 
     /// Get the number of stored keys
@@ -181,6 +540,48 @@ impl KeyStore {
     /// Get key by index
     /// Returns Some(key) if index is valid, None if out of range
     pub fn get(&self, index: usize) -> Option<&[u8; 33]> {
-        self.keys.get(index)
+        self.keys.get(index).map(|stored| &stored.key)
     }
+
+    /// Validity window of the key at `index`
+    pub fn get_validity(&self, index: usize) -> Option<Validity> {
+        self.keys.get(index).map(|stored| stored.validity)
+    }
+}
+
+/// Read the info of one key slot, all zeros if it has none
+async fn load_info<S: NorFlash>(flash: &mut S, id: u16) -> Result<KeyInfo, sequential_storage::Error<S::Error>> {
+    let mut cache = NoCache::new();
+    let mut buf = [0u8; 64];
+    let value = map::fetch_item::<u16, &[u8], _>(flash, INFO_RANGE, &mut cache, &mut buf, &id).await?;
+    Ok(value.and_then(KeyInfo::from_bytes).unwrap_or_default())
+}
+
+/// Write the info of one key slot
+async fn store_info<S: NorFlash>(flash: &mut S, id: u16, info: &KeyInfo) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut cache = NoCache::new();
+    let mut buf = [0u8; 64];
+    map::store_item::<u16, [u8; KEY_INFO_LEN], _>(flash, INFO_RANGE, &mut cache, &mut buf, &id, &info.to_bytes()).await
+}
+
+/// Write the record of one key slot, an empty value is a tombstone
+async fn store_record<S: NorFlash>(flash: &mut S, id: u16, value: &[u8]) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut cache = NoCache::new();
+    let mut buf = [0u8; 64];
+    map::store_item::<u16, &[u8], _>(flash, FLASH_RANGE, &mut cache, &mut buf, &id, &value).await
+}
+
+/// Read the use counter of one key slot, None if it has none
+async fn load_uses<S: NorFlash>(flash: &mut S, id: u16) -> Result<Option<u32>, sequential_storage::Error<S::Error>> {
+    let mut cache = NoCache::new();
+    let mut buf = [0u8; 64];
+    let value = map::fetch_item::<u16, &[u8], _>(flash, USES_RANGE, &mut cache, &mut buf, &id).await?;
+    Ok(value.and_then(|value| value.try_into().ok()).map(u32::from_le_bytes))
+}
+
+/// Write the use counter of one key slot, an empty value is a tombstone
+async fn store_uses<S: NorFlash>(flash: &mut S, id: u16, value: &[u8]) -> Result<(), sequential_storage::Error<S::Error>> {
+    let mut cache = NoCache::new();
+    let mut buf = [0u8; 64];
+    map::store_item::<u16, &[u8], _>(flash, USES_RANGE, &mut cache, &mut buf, &id, &value).await
 }
@@ -1,8 +1,23 @@
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
+pub mod authlog;
+pub mod board;
+#[cfg(feature = "esp")]
 pub mod ble_bas_peripheral;
+pub mod clock;
+pub mod faultlog;
 pub mod fsm;
 pub mod gpi;
 pub mod gpo;
 pub mod keys;
+pub mod lockout;
+pub mod schedule;
 pub mod settings;
+#[cfg(feature = "std")]
+pub mod sim;
 pub mod types;
+
+// Console output: esp-println on the target, stdout in the host simulation
+#[cfg(feature = "esp")]
+pub(crate) use esp_println::println;
+#[cfg(all(feature = "std", not(feature = "esp")))]
+pub(crate) use std::println;
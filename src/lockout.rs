@@ -0,0 +1,155 @@
+//! Failed authentication tracking with exponential lockout
+//!
+//! Failures are counted per peer address and globally (phones rotate their
+//! random addresses, so the per-peer counter alone is easy to sidestep).
+//! State lives in RAM for the lifetime of the BLE stack, so it survives reconnects.
+//!
+//! Attempts rejected by a lockout are not written to the auth log one by one:
+//! only the first of each lockout is, so a peer hammering the characteristic
+//! cannot wear the flash or push real entries out of the log.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+
+/// Number of peer addresses tracked at once; the least recently failing one is evicted
+const MAX_PEERS: usize = 16;
+
+/// Failures allowed per peer before lockout starts
+const PEER_FREE_ATTEMPTS: u32 = 3;
+/// Failures allowed across all peers before the global lockout starts
+const GLOBAL_FREE_ATTEMPTS: u32 = 20;
+
+/// First lockout period, doubled on every further failure
+const BASE_LOCKOUT: Duration = Duration::from_secs(2);
+/// Upper bound for the per-peer lockout
+const MAX_PEER_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+/// Upper bound for the global lockout (it also blocks legitimate users)
+const MAX_GLOBAL_LOCKOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Counters are forgotten after this long without a failure
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+struct FailureCounter {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Instant,
+}
+
+impl FailureCounter {
+    const fn new() -> Self {
+        Self {
+            failures: 0,
+            last_failure: Instant::MIN,
+            locked_until: Instant::MIN,
+        }
+    }
+
+    fn remaining(&self, now: Instant) -> Option<Duration> {
+        if now < self.locked_until {
+            Some(self.locked_until - now)
+        } else {
+            None
+        }
+    }
+
+    fn fail(&mut self, now: Instant, free: u32, max: Duration) {
+        if self.failures > 0 && now.saturating_duration_since(self.last_failure) >= FORGET_AFTER {
+            self.failures = 0;
+        }
+        self.failures = self.failures.saturating_add(1);
+        self.last_failure = now;
+        if self.failures >= free {
+            let shift = (self.failures - free).min(16);
+            let lockout = Duration::from_ticks(BASE_LOCKOUT.as_ticks() << shift);
+            self.locked_until = now + if lockout > max { max } else { lockout };
+        }
+    }
+}
+
+struct PeerRecord {
+    addr: [u8; 6],
+    counter: FailureCounter,
+}
+
+/// Brute-force limiter for the authenticate characteristic
+pub struct AuthLimiter {
+    peers: Vec<PeerRecord, MAX_PEERS>,
+    global: FailureCounter,
+    /// End of the last lockout a rejected attempt was logged for
+    logged_until: Instant,
+}
+
+impl AuthLimiter {
+    pub const fn new() -> Self {
+        Self {
+            peers: Vec::new(),
+            global: FailureCounter::new(),
+            logged_until: Instant::MIN,
+        }
+    }
+
+    /// Returns the remaining lockout for this peer, or None if an attempt is allowed
+    pub fn check(&self, addr: &[u8; 6], now: Instant) -> Option<Duration> {
+        let peer = self
+            .peers
+            .iter()
+            .find(|p| &p.addr == addr)
+            .and_then(|p| p.counter.remaining(now));
+        match (peer, self.global.remaining(now)) {
+            (Some(a), Some(b)) => Some(if a > b { a } else { b }),
+            (a, b) => a.or(b),
+        }
+    }
+
+    /// Whether an attempt rejected with `remaining` lockout left (as returned by
+    /// `check`) goes to the auth log
+    /// True for the first rejection of a lockout; later ones, from any peer, are
+    /// merged into it until a lockout ending later starts
+    pub fn log_rejection(&mut self, now: Instant, remaining: Duration) -> bool {
+        let until = now + remaining;
+        if until > self.logged_until {
+            self.logged_until = until;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Record a failed attempt and extend the lockout
+    pub fn record_failure(&mut self, addr: &[u8; 6], now: Instant) {
+        self.global.fail(now, GLOBAL_FREE_ATTEMPTS, MAX_GLOBAL_LOCKOUT);
+
+        if let Some(peer) = self.peers.iter_mut().find(|p| &p.addr == addr) {
+            peer.counter.fail(now, PEER_FREE_ATTEMPTS, MAX_PEER_LOCKOUT);
+            return;
+        }
+
+        if self.peers.is_full() {
+            // Evict the peer that failed least recently
+            if let Some(oldest) = self
+                .peers
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, p)| p.counter.last_failure)
+                .map(|(i, _)| i)
+            {
+                self.peers.swap_remove(oldest);
+            }
+        }
+        let mut counter = FailureCounter::new();
+        counter.fail(now, PEER_FREE_ATTEMPTS, MAX_PEER_LOCKOUT);
+        let _ = self.peers.push(PeerRecord { addr: *addr, counter });
+    }
+
+    /// Record a successful attempt, clearing the peer and global counters
+    pub fn record_success(&mut self, addr: &[u8; 6]) {
+        self.peers.retain(|p| &p.addr != addr);
+        self.global = FailureCounter::new();
+    }
+}
+
+impl Default for AuthLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -2,18 +2,24 @@
 #![no_main]
 
 use blue_gate::ble_bas_peripheral;
+use blue_gate::board::BoardProfile;
 use blue_gate::fsm::{fsm_task, FSM_COMMAND_CHANNEL};
 use blue_gate::gpi::gpi_task;
 use embassy_time::{Duration};
-use blue_gate::gpo::gpo_task;
+use blue_gate::gpo::{
+    gpo_task, POLARITY_LAMP, POLARITY_LEFT_CLOSE, POLARITY_LEFT_OPEN, POLARITY_RIGHT_CLOSE, POLARITY_RIGHT_OPEN,
+};
 use blue_gate::keys::KeyStore;
+use blue_gate::schedule::{self, ScheduleStore};
 use blue_gate::settings::{ConfigStore, ConfigSlot};
-use blue_gate::types::GateConfig;
+use blue_gate::types::{
+    ControlAction, ControlMode, Door, DriveMode, GateConfig, GpiConfig, GpoConfig, LampTimings, PulseConfig, RelayOutput,
+};
 use embassy_executor::Spawner;
 use esp_backtrace as _;
 use esp_hal::{
     clock::CpuClock,
-    gpio::{Input, InputConfig, Level, Output, OutputConfig, Pull},
+    gpio::{AnyPin, Input, InputConfig, Level, Output, OutputConfig, Pull},
     rng::{Trng, TrngSource},
     timer::timg::TimerGroup,
 };
@@ -30,13 +36,6 @@ async fn main(spawner: Spawner) {
     let peripherals = esp_hal::init(esp_hal::Config::default().with_cpu_clock(CpuClock::max()));
     esp_alloc::heap_allocator!(size: 72 * 1024);
 
-    // Output pins for GPO task
-    let lamp = Output::new(peripherals.GPIO7,   Level::Low, OutputConfig::default());
-    let lclose = Output::new(peripherals.GPIO1, Level::Low, OutputConfig::default());
-    let lopen = Output::new(peripherals.GPIO2,  Level::Low, OutputConfig::default());
-    let rclose = Output::new(peripherals.GPIO4, Level::Low, OutputConfig::default());
-    let ropen = Output::new(peripherals.GPIO3,  Level::Low, OutputConfig::default());
-
     let timg0 = TimerGroup::new(peripherals.TIMG0);
     #[cfg(target_arch = "riscv32")]
     let software_interrupt =
@@ -61,36 +60,106 @@ async fn main(spawner: Spawner) {
     // Initialize stores from flash (keys takes ownership, config created after keys loads data)
     let (keys, flash) = KeyStore::new(flash).await;
     println!("Loaded {} keys from flash", keys.len());
+    let (mut schedules, flash) = ScheduleStore::new(flash).await;
     let mut config = ConfigStore::new(flash).await;
+    schedules.set_utc_offset(schedule::utc_offset_from_config(config.get(ConfigSlot::UtcOffset,0).await).unwrap_or(0));
     let polarity: u32 = config.get(ConfigSlot::IOPolarity,0).await;
     println!("Polarity mask {}",polarity);
 
+    // Board profile maps every input and output to its GPIO
+    let board = BoardProfile::from_config(config.get(ConfigSlot::BoardProfile,0).await);
+    println!("Board profile {}", board.name);
+    // SAFETY: the GPIOs of a profile are distinct and not used by any other driver
+    let gpio = |n: u8| unsafe { AnyPin::steal(n) };
+
+    // Output pins for GPO task, starting at their inactive level
+    let output = |n: u8, active_low: u32| {
+        let idle = if polarity & active_low != 0 { Level::High } else { Level::Low };
+        Output::new(gpio(n), idle, OutputConfig::default())
+    };
+    let lamp = output(board.lamp, POLARITY_LAMP);
+    let lopen = output(board.left_open, POLARITY_LEFT_OPEN);
+    let lclose = output(board.left_close, POLARITY_LEFT_CLOSE);
+    let ropen = output(board.right_open, POLARITY_RIGHT_OPEN);
+    let rclose = output(board.right_close, POLARITY_RIGHT_CLOSE);
+
     // Input pins for GPI task
     let trigger = Input::new(
-        peripherals.GPIO15,
+        gpio(board.control),
         InputConfig::default().with_pull(Pull::Down),
     );
 
     let obstacle = Input::new(
-        peripherals.GPIO14,
+        gpio(board.obstacle),
         InputConfig::default().with_pull(Pull::Down),
     );
 
     let prog_mode = Input::new(
-        peripherals.GPIO20,
+        gpio(board.prog_mode),
         InputConfig::default().with_pull(Pull::Up),
     );
 
-    // Spawn GPI task (monitors trigger and obstacle inputs)
-    spawner.spawn(gpi_task(trigger, obstacle, polarity >> 8)).unwrap();
+    // Optional second control input, opens the pedestrian leaf
+    let pedestrian = if config.get(ConfigSlot::PedestrianInput,0).await != 0 {
+        Some(Input::new(
+            gpio(board.pedestrian),
+            InputConfig::default().with_pull(Pull::Down),
+        ))
+    } else {
+        None
+    };
+
+    // Optional limit switches: left open, left close, right open, right close
+    // Pulled up, so an unconnected input reads inactive with the default (active low) polarity
+    let limit_switches = config.get(ConfigSlot::LimitSwitches,0).await != 0;
+    let limits = if limit_switches {
+        let limit_config = InputConfig::default().with_pull(Pull::Up);
+        Some(board.limits.map(|n| Input::new(gpio(n), limit_config)))
+    } else {
+        None
+    };
+
+    // Spawn GPI task (monitors trigger, obstacle, pedestrian and limit inputs)
+    let millis = |ms: u32| Duration::from_millis(ms.into());
+    let gpi_config = GpiConfig {
+        polarity: polarity >> 8,
+        control_debounce: millis(config.get(ConfigSlot::ControlDebounce,100).await),
+        long_press: millis(config.get(ConfigSlot::LongPressTime,2000).await),
+        click_gap: millis(config.get(ConfigSlot::ClickGap,500).await),
+        obstacle_detect_debounce: millis(config.get(ConfigSlot::ObstacleDetectDebounce,20).await),
+        obstacle_debounce: millis(config.get(ConfigSlot::ObstacleDebounce,100).await),
+        pedestrian_debounce: millis(config.get(ConfigSlot::PedestrianDebounce,100).await),
+        limit_debounce: millis(config.get(ConfigSlot::LimitDebounce,100).await),
+    };
+    spawner.spawn(gpi_task(trigger, obstacle, pedestrian, limits, gpi_config)).unwrap();
 
     // Spawn GPO task (controls door relays and lamp)
+    let relay_dead_time = Duration::from_millis(config.get(ConfigSlot::RelayDeadTime,500).await.into());
+    let lamp_timings = LampTimings {
+        blink_on: Duration::from_millis(config.get(ConfigSlot::LampBlinkOn,1500).await.into()),
+        blink_off: Duration::from_millis(config.get(ConfigSlot::LampBlinkOff,500).await.into()),
+        prestart_on: Duration::from_millis(config.get(ConfigSlot::LampPrestartOn,250).await.into()),
+        prestart_off: Duration::from_millis(config.get(ConfigSlot::LampPrestartOff,250).await.into()),
+        fault_flash: Duration::from_millis(config.get(ConfigSlot::LampFaultFlash,250).await.into()),
+        fault_pause: Duration::from_millis(config.get(ConfigSlot::LampFaultPause,1500).await.into()),
+    };
+    let pulse = PulseConfig {
+        width: Duration::from_millis(config.get(ConfigSlot::PulseWidth,500).await.into()),
+        open: RelayOutput::from_config(config.get(ConfigSlot::PulseOpenRelay,0).await),
+        close: RelayOutput::from_config(config.get(ConfigSlot::PulseCloseRelay,1).await),
+        stop: RelayOutput::from_config(config.get(ConfigSlot::PulseStopRelay,2).await),
+    };
+    let gpo_config = GpoConfig { polarity, relay_dead_time, lamp: lamp_timings, pulse };
     spawner
-        .spawn(gpo_task(lamp, lopen, lclose, ropen, rclose, polarity))
+        .spawn(gpo_task(lamp, lopen, lclose, ropen, rclose, gpo_config))
         .unwrap();
 
     // Spawn FSM task with default gate configuration
-    let gate_config = GateConfig{
+    let pedestrian_door = match config.get(ConfigSlot::PedestrianDoor,0).await {
+        0 => Door::Left,
+        _ => Door::Right,
+    };
+    let mut gate_config = GateConfig{
                 left_door: blue_gate::types::DoorConfig::new(
                     Duration::from_millis(config.get(ConfigSlot::LeftOpenDelay,100).await.into()),
                     Duration::from_millis(config.get(ConfigSlot::LeftCloseDelay,800).await.into()),
@@ -108,7 +177,25 @@ async fn main(spawner: Spawner) {
                     n => Some(Duration::from_millis(n.into()))
                 },
                 lamp_prestart:  Duration::from_millis(config.get(ConfigSlot::LampPreStart,500).await.into()),
+                control_mode: ControlMode::from_config(config.get(ConfigSlot::ControlMode,0).await),
+                control_actions: [
+                    ControlAction::from_config(config.get(ConfigSlot::ShortPressAction,1).await),
+                    ControlAction::from_config(config.get(ConfigSlot::LongPressAction,1).await),
+                    ControlAction::from_config(config.get(ConfigSlot::DoubleClickAction,1).await),
+                    ControlAction::from_config(config.get(ConfigSlot::TripleClickAction,3).await),
+                ],
+                pedestrian_door,
+                pedestrian_travel: Duration::from_millis(0),
+                limit_switches,
+                obstacle_stuck_after: match config.get(ConfigSlot::ObstacleStuckTime,300).await {
+                    0 => None,
+                    n => Some(Duration::from_secs(n.into()))
+                },
+                drive_mode: DriveMode::from_config(config.get(ConfigSlot::DriveMode,0).await),
             }; //GateConfig::default();
+    let half_open = gate_config.door(pedestrian_door).open_duration.as_millis() as u32 / 2;
+    gate_config.pedestrian_travel =
+        Duration::from_millis(config.get(ConfigSlot::PedestrianTravel,half_open).await.into());
 
     spawner.spawn(fsm_task(gate_config)).unwrap();
 
@@ -120,5 +207,5 @@ async fn main(spawner: Spawner) {
     println!("Device name: {}", device_name.as_str());
 
     // Run BLE peripheral
-    ble_bas_peripheral::run(controller, &mut trng, &device_name, keys, config, cmdtx, prog_mode.is_low()).await;
+    ble_bas_peripheral::run(controller, &mut trng, &device_name, keys, schedules, config, cmdtx, prog_mode.is_low()).await;
 }
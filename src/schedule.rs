@@ -0,0 +1,184 @@
+//! Weekly access schedules
+//!
+//! A schedule profile is a few weekly time windows, e.g. Monday to Friday
+//! 07:00-19:00. Keys refer to a profile by its ID and are only accepted inside
+//! one of its windows. Profiles are kept in RAM and persisted as one
+//! `sequential_storage::map` record per profile, an empty record deletes one.
+//!
+//! Windows are in local time: the device clock (UTC) shifted by the UTC offset
+//! setting, which is shared by all profiles.
+
+use crate::clock::DateTime;
+use embedded_storage_async::nor_flash::NorFlash;
+use sequential_storage::cache::NoCache;
+use sequential_storage::map;
+
+/// Flash storage range for the schedule profiles
+/// 8KB (2 pages) directly before the fault history range (0x3D4000..0x3D6000)
+const FLASH_RANGE: core::ops::Range<u32> = 0x3D2000..0x3D4000;
+
+/// Number of profiles, IDs 1..=MAX_SCHEDULES (0 is "no schedule" on a key)
+pub const MAX_SCHEDULES: usize = 32;
+/// Windows per profile
+pub const WINDOWS: usize = 4;
+/// Serialized profile length (also the size of the `management_schedule` characteristic)
+pub const SCHEDULE_LEN: usize = WINDOWS * 5;
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// UTC offsets (minutes) of local time, UTC-12:00 to UTC+14:00
+const UTC_OFFSETS: core::ops::RangeInclusive<i32> = -12 * 60..=14 * 60;
+
+/// UTC offset (minutes) from its `ConfigSlot::UtcOffset` value, a two's complement i32
+/// None if it is out of range
+pub fn utc_offset_from_config(value: u32) -> Option<i32> {
+    let minutes = value as i32;
+    UTC_OFFSETS.contains(&minutes).then_some(minutes)
+}
+
+/// Recurring weekly time window, times in minutes since local midnight
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Window {
+    /// Bit 0 = Monday .. bit 6 = Sunday, 0 = unused window
+    pub days: u8,
+    /// First minute of the window
+    pub start: u16,
+    /// First minute after the window, up to 1440
+    pub end: u16,
+}
+
+impl Window {
+    fn is_valid(&self) -> bool {
+        self.days == 0 || (self.days & 0x80 == 0 && self.start < self.end && self.end <= MINUTES_PER_DAY)
+    }
+
+    fn contains(&self, time: &DateTime) -> bool {
+        let minute = time.hour as u16 * 60 + time.minute as u16;
+        self.days & (1 << (time.weekday() - 1)) != 0 && (self.start..self.end).contains(&minute)
+    }
+}
+
+/// Schedule profile: a key is accepted inside any of the windows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Schedule {
+    pub windows: [Window; WINDOWS],
+}
+
+impl Schedule {
+    /// Check whether `now` (Unix seconds) falls into one of the windows, in
+    /// local time `utc_offset` minutes from UTC
+    pub fn allows(&self, now: u64, utc_offset: i32) -> bool {
+        let local = DateTime::from_unix(now.saturating_add_signed(utc_offset as i64 * 60));
+        self.windows.iter().any(|w| w.contains(&local))
+    }
+
+    /// Serialize to the wire/flash format: per window days, start (u16 LE), end (u16 LE)
+    pub fn to_bytes(&self) -> [u8; SCHEDULE_LEN] {
+        let mut out = [0u8; SCHEDULE_LEN];
+        for (w, chunk) in self.windows.iter().zip(out.chunks_exact_mut(5)) {
+            chunk[0] = w.days;
+            chunk[1..3].copy_from_slice(&w.start.to_le_bytes());
+            chunk[3..5].copy_from_slice(&w.end.to_le_bytes());
+        }
+        out
+    }
+
+    /// Parse a profile written by `to_bytes`, None if a window is out of range
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() != SCHEDULE_LEN {
+            return None;
+        }
+        let mut schedule = Self::default();
+        for (w, chunk) in schedule.windows.iter_mut().zip(data.chunks_exact(5)) {
+            *w = Window {
+                days: chunk[0],
+                start: u16::from_le_bytes([chunk[1], chunk[2]]),
+                end: u16::from_le_bytes([chunk[3], chunk[4]]),
+            };
+        }
+        schedule.windows.iter().all(Window::is_valid).then_some(schedule)
+    }
+
+    /// A profile without any window is never written, it deletes the profile instead
+    pub fn is_empty(&self) -> bool {
+        self.windows.iter().all(|w| w.days == 0)
+    }
+}
+
+/// Schedule profiles, held in RAM and persisted to flash
+pub struct ScheduleStore {
+    schedules: [Option<Schedule>; MAX_SCHEDULES],
+    /// Local time of the windows, minutes from UTC
+    utc_offset: i32,
+}
+
+impl ScheduleStore {
+    /// Create a new ScheduleStore and load existing profiles from flash
+    /// Returns the ScheduleStore and gives back flash ownership
+    pub async fn new<S: NorFlash>(mut flash: S) -> (Self, S) {
+        let mut store = Self { schedules: [None; MAX_SCHEDULES], utc_offset: 0 };
+        // Profiles read before an error are kept
+        let _ = store.load_from_flash(&mut flash).await;
+        (store, flash)
+    }
+
+    async fn load_from_flash<S: NorFlash>(&mut self, flash: &mut S) -> Result<(), sequential_storage::Error<S::Error>> {
+        let mut cache = NoCache::new();
+        let mut buf = [0u8; 32];
+        let mut records = map::fetch_all_items::<u8, _, _>(flash, FLASH_RANGE, &mut cache, &mut buf).await?;
+        // Records come oldest first, a later record for an ID replaces the earlier one
+        while let Some((id, value)) = records.next::<&[u8]>(&mut buf).await? {
+            if let Some(slot) = Self::index(id).and_then(|i| self.schedules.get_mut(i)) {
+                *slot = Schedule::from_bytes(value);
+            }
+        }
+        Ok(())
+    }
+
+    fn index(id: u8) -> Option<usize> {
+        (1..=MAX_SCHEDULES).contains(&(id as usize)).then(|| id as usize - 1)
+    }
+
+    pub fn is_valid_id(id: u8) -> bool {
+        Self::index(id).is_some()
+    }
+
+    /// Profile `id`, None if not defined
+    pub fn get(&self, id: u8) -> Option<&Schedule> {
+        Self::index(id).and_then(|i| self.schedules[i].as_ref())
+    }
+
+    /// Check whether profile `id` exists and allows `now` (Unix seconds)
+    pub fn allows(&self, id: u8, now: u64) -> bool {
+        self.get(id).is_some_and(|s| s.allows(now, self.utc_offset))
+    }
+
+    pub fn utc_offset(&self) -> i32 {
+        self.utc_offset
+    }
+
+    /// Set the local time of the windows, minutes from UTC (see `utc_offset_from_config`)
+    pub fn set_utc_offset(&mut self, minutes: i32) {
+        self.utc_offset = minutes;
+    }
+
+    /// Store profile `id`, an empty schedule deletes it
+    /// Returns Ok(false) if `id` is out of range
+    pub async fn set<S: NorFlash>(
+        &mut self,
+        flash: &mut S,
+        id: u8,
+        schedule: Schedule,
+    ) -> Result<bool, sequential_storage::Error<S::Error>> {
+        let Some(i) = Self::index(id) else {
+            return Ok(false);
+        };
+        let bytes = schedule.to_bytes();
+        let value: &[u8] = if schedule.is_empty() { &[] } else { &bytes };
+        let mut cache = NoCache::new();
+        let mut buf = [0u8; 32];
+        map::store_item::<u8, &[u8], _>(flash, FLASH_RANGE, &mut cache, &mut buf, &id, &value).await?;
+        self.schedules[i] = (!schedule.is_empty()).then_some(schedule);
+        Ok(true)
+    }
+}
@@ -1,5 +1,5 @@
 use embedded_storage_async::nor_flash::NorFlash;
-use esp_println::println;
+use crate::println;
 use heapless::String;
 use sequential_storage::cache::NoCache;
 use sequential_storage::map;
@@ -7,7 +7,7 @@ use sequential_storage::map;
 /// Flash storage range for settings (separate from keys storage)
 /// Must be at least 2× erase size (2 × 4KB = 8KB minimum)
 /// Using 8KB before the keys storage area
-/// Keys use 0x3E0000..0x3F0000 (64KB)
+/// Keys use 0x3E0000..0x3F0000 (64KB), the auth log uses 0x3D6000..0x3DE000 (32KB)
 const FLASH_RANGE: core::ops::Range<u32> = 0x3DE000..0x3E0000;
 
 /// Maximum length for device name string
@@ -22,10 +22,18 @@ const NAME_SLOT_ID: u8 = 255;
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum ConfigSlot {
     Pad = 0,
+    /// Bits 0-4: lamp, left open, left close, right open, right close outputs (set = active low)
+    /// Bits 8-14: control, obstacle, pedestrian, limit switch inputs (set = active high)
     IOPolarity = 1,
     LampPreStart = 2,
     ConnTimeout = 3,
     AutoClose = 4,
+    /// Non-zero: also accept the legacy v1 challenge (nonce pair only) for old apps
+    AuthLegacy = 5,
+    /// Number of auth log entries kept in flash (1..=400, default 200)
+    LogCapacity = 6,
+    /// Non-zero: any authenticated key may set the clock, not only admins
+    TimeSyncAnyKey = 7,
 
     LeftOpenDelay = 8,
     LeftOpenDuration = 9,
@@ -36,6 +44,65 @@ pub enum ConfigSlot {
     LeftCloseDuration = 13,
     RightCloseDelay = 14,
     RightCloseDuration = 15,
+
+    /// Control input mode: 0 = standard, 1 = step-by-step (open/stop/close/stop)
+    ControlMode = 16,
+    /// Door opened by the pedestrian command: 0 = left, 1 = right
+    PedestrianDoor = 17,
+    /// Open relay time of the pedestrian leaf in ms (default: half its open duration)
+    PedestrianTravel = 18,
+    /// Non-zero: enable the second control input (pedestrian open)
+    PedestrianInput = 19,
+    /// Non-zero: doors have open/close limit switches (durations become travel timeouts)
+    LimitSwitches = 20,
+    /// Obstacle input active for longer than this (s) faults the next close, 0 = off
+    ObstacleStuckTime = 21,
+    /// Both relays of a door stay off for this long (ms) on a direction change
+    RelayDeadTime = 22,
+    /// Lamp on/off times (ms) while the doors move, off 0 = steady
+    LampBlinkOn = 23,
+    LampBlinkOff = 24,
+    /// Lamp on/off times (ms) during the prestart, off 0 = steady
+    LampPrestartOn = 25,
+    LampPrestartOff = 26,
+    /// Lamp on/off time (ms) of each fault code flash
+    LampFaultFlash = 27,
+    /// Lamp off time (ms) between fault code sequences
+    LampFaultPause = 28,
+    /// GPIO mapping, index into `board::PROFILES` (0 = rev 1 board)
+    BoardProfile = 29,
+    /// Gate drive: 0 = timed door relays, 1 = pulses for a third-party controller
+    DriveMode = 30,
+    /// Not a setting: writing this slot over BLE reboots the device
+    Reset = 31,
+    /// Pulse mode relay closure time (ms)
+    PulseWidth = 32,
+    /// Relay pulsed for open/close/stop in pulse mode:
+    /// 0 left open, 1 left close, 2 right open, 3 right close, other = none
+    PulseOpenRelay = 33,
+    PulseCloseRelay = 34,
+    PulseStopRelay = 35,
+    /// Time (ms) a new input level must hold before it is accepted
+    ControlDebounce = 36,
+    /// Obstacle clearing debounce (ms), detection uses `ObstacleDetectDebounce`
+    ObstacleDebounce = 37,
+    PedestrianDebounce = 38,
+    LimitDebounce = 39,
+    /// Control input hold time (ms) of a long press, 0 = no long presses
+    LongPressTime = 40,
+    /// Longest gap (ms) between the clicks of a double/triple click, 0 = single clicks only
+    ClickGap = 41,
+    /// Control gesture actions: 0 none, 1 control pulse (per `ControlMode`), 2 open,
+    /// 3 close, 4 stop, 5 pedestrian open, 6 stop autoclose
+    ShortPressAction = 42,
+    LongPressAction = 43,
+    DoubleClickAction = 44,
+    TripleClickAction = 45,
+    /// Time (ms) an obstacle must be present before it is reported
+    ObstacleDetectDebounce = 46,
+    /// Local time of the schedule windows: minutes from UTC as a two's complement i32,
+    /// -720..=840 (e.g. 60 for UTC+1, 0xFFFFFED4 for UTC-5)
+    UtcOffset = 47,
 }
 
 impl ConfigSlot {
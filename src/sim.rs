@@ -0,0 +1,544 @@
+//! Host-side simulation of the gate logic
+//!
+//! Runs the FSM, GPI and GPO loops against mock pins on embassy-time's mock
+//! driver, so scripted input timelines can be replayed in virtual time and the
+//! resulting output timelines checked with `cargo test`.
+//!
+//! Virtual time advances in 1 ms steps; between steps all tasks are polled until
+//! they settle. Output transitions are recorded with the virtual time they
+//! happened at, state changes as published by the FSM.
+//!
+//! `MockFlash` stands in for the NOR flash of the storage modules, with power
+//! cuts at chosen write offsets for crash-consistency tests.
+
+use std::cell::{Cell, RefCell};
+use std::convert::Infallible;
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::vec::Vec;
+
+use embassy_futures::join::join3;
+use embassy_futures::select::select;
+use embassy_futures::yield_now;
+use embassy_time::{Duration, Instant, MockDriver};
+use embedded_hal::digital::{ErrorType, InputPin, OutputPin};
+use embedded_hal_async::digital::Wait;
+use embedded_storage_async::nor_flash::{self, MultiwriteNorFlash, NorFlash, NorFlashErrorKind, ReadNorFlash};
+
+use crate::fsm::{run_fsm, subscribe_state, FAULT_EVENTS, FSM_COMMAND_CHANNEL};
+use crate::gpi::{run_gpi, GPI_CHANNEL};
+use crate::gpo::{run_gpo, GPO_CHANNEL};
+use crate::types::{Door, FsmCommand, GateConfig, GpiConfig, GpoCommand, GpoConfig, LampTimings, PulseConfig, StateChange};
+
+/// Polls per virtual millisecond, enough for an event to pass GPI -> FSM -> GPO
+const SETTLE_POLLS: usize = 16;
+
+/// Length of the control pulse generated by `Simulation::pulse`
+pub const PULSE_LENGTH_MS: u64 = 200;
+
+/// The mock driver and the task channels are global, so simulations run one at a time
+static SIM_LOCK: Mutex<()> = Mutex::new(());
+
+/// Input pin driven by the simulation script
+#[derive(Clone, Default)]
+pub struct MockInput {
+    high: Rc<Cell<bool>>,
+}
+
+impl MockInput {
+    pub fn set_high(&self, high: bool) {
+        self.high.set(high);
+    }
+}
+
+impl ErrorType for MockInput {
+    type Error = Infallible;
+}
+
+impl InputPin for MockInput {
+    fn is_high(&mut self) -> Result<bool, Self::Error> {
+        Ok(self.high.get())
+    }
+
+    fn is_low(&mut self) -> Result<bool, Self::Error> {
+        Ok(!self.high.get())
+    }
+}
+
+/// Edge waits check the level on every poll, the simulation polls all tasks each step
+impl Wait for MockInput {
+    async fn wait_for_high(&mut self) -> Result<(), Self::Error> {
+        while !self.high.get() {
+            yield_now().await;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_low(&mut self) -> Result<(), Self::Error> {
+        while self.high.get() {
+            yield_now().await;
+        }
+        Ok(())
+    }
+
+    async fn wait_for_rising_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_low().await?;
+        self.wait_for_high().await
+    }
+
+    async fn wait_for_falling_edge(&mut self) -> Result<(), Self::Error> {
+        self.wait_for_high().await?;
+        self.wait_for_low().await
+    }
+
+    async fn wait_for_any_edge(&mut self) -> Result<(), Self::Error> {
+        if self.high.get() {
+            self.wait_for_low().await
+        } else {
+            self.wait_for_high().await
+        }
+    }
+}
+
+/// Output pin recording its level changes as (virtual ms, high)
+#[derive(Clone, Default)]
+pub struct MockOutput {
+    high: Rc<Cell<bool>>,
+    edges: Rc<RefCell<Vec<(u64, bool)>>>,
+}
+
+impl MockOutput {
+    pub fn is_set_high(&self) -> bool {
+        self.high.get()
+    }
+
+    /// Recorded level changes, the pin starts low
+    pub fn edges(&self) -> Vec<(u64, bool)> {
+        self.edges.borrow().clone()
+    }
+
+    fn set(&mut self, high: bool) {
+        if self.high.get() != high {
+            self.high.set(high);
+            self.edges.borrow_mut().push((Instant::now().as_millis(), high));
+        }
+    }
+}
+
+impl ErrorType for MockOutput {
+    type Error = Infallible;
+}
+
+impl OutputPin for MockOutput {
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.set(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.set(true);
+        Ok(())
+    }
+}
+
+/// Size of the simulated flash, covering every storage range of the 4 MB part
+const FLASH_SIZE: usize = 0x400000;
+
+/// NOR flash in memory: writes can only clear bits, erases set whole pages
+/// A power cut can be armed to tear a write after a number of bytes; from then
+/// on every access fails until power is restored
+#[derive(Clone)]
+pub struct MockFlash {
+    data: Vec<u8>,
+    /// Bytes written so far
+    written: usize,
+    /// Bytes read so far
+    read: usize,
+    /// Bytes that can still be written before the power cut
+    cut_after: Option<usize>,
+    powered: bool,
+}
+
+impl Default for MockFlash {
+    fn default() -> Self {
+        Self {
+            data: vec![0xff; FLASH_SIZE],
+            written: 0,
+            read: 0,
+            cut_after: None,
+            powered: true,
+        }
+    }
+}
+
+impl MockFlash {
+    /// Total bytes written, to count the flash wear of an operation
+    pub fn bytes_written(&self) -> usize {
+        self.written
+    }
+
+    /// Total bytes read, to count the flash accesses of an operation
+    pub fn bytes_read(&self) -> usize {
+        self.read
+    }
+
+    /// Cut the power once `bytes` more bytes have been written
+    pub fn cut_power_after(&mut self, bytes: usize) {
+        self.cut_after = Some(bytes);
+    }
+
+    /// Power back on, as after a restart; a torn write stays torn
+    pub fn restore_power(&mut self) {
+        self.cut_after = None;
+        self.powered = true;
+    }
+
+    fn check(&self, offset: u32, len: usize, align: usize) -> Result<(), NorFlashErrorKind> {
+        if !self.powered {
+            return Err(NorFlashErrorKind::Other);
+        }
+        if !(offset as usize).is_multiple_of(align) || !len.is_multiple_of(align) {
+            return Err(NorFlashErrorKind::NotAligned);
+        }
+        if offset as usize + len > self.data.len() {
+            return Err(NorFlashErrorKind::OutOfBounds);
+        }
+        Ok(())
+    }
+}
+
+impl nor_flash::ErrorType for MockFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for MockFlash {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.check(offset, bytes.len(), Self::READ_SIZE)?;
+        let start = offset as usize;
+        bytes.copy_from_slice(&self.data[start..start + bytes.len()]);
+        self.read += bytes.len();
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for MockFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.check(from, (to - from) as usize, Self::ERASE_SIZE)?;
+        self.data[from as usize..to as usize].fill(0xff);
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.check(offset, bytes.len(), Self::WRITE_SIZE)?;
+        let len = match self.cut_after {
+            Some(left) if left < bytes.len() => left,
+            _ => bytes.len(),
+        };
+        let start = offset as usize;
+        for (cell, byte) in self.data[start..start + len].iter_mut().zip(bytes) {
+            *cell &= byte;
+        }
+        self.written += len;
+        if let Some(left) = self.cut_after.as_mut() {
+            *left -= len;
+            if len < bytes.len() {
+                self.powered = false;
+                return Err(NorFlashErrorKind::Other);
+            }
+        }
+        Ok(())
+    }
+}
+
+// Writes only clear bits, so a word can be written again like on the real part
+impl MultiwriteNorFlash for MockFlash {}
+
+/// Simulated outputs, in the order `gpo_task` takes them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Relay {
+    Lamp,
+    LeftOpen,
+    LeftClose,
+    RightOpen,
+    RightClose,
+}
+
+impl Relay {
+    pub const ALL: [Relay; 5] = [
+        Relay::Lamp,
+        Relay::LeftOpen,
+        Relay::LeftClose,
+        Relay::RightOpen,
+        Relay::RightClose,
+    ];
+}
+
+/// Scripted input
+#[derive(Debug, Clone, Copy)]
+pub enum Stimulus {
+    /// Control input active/inactive
+    Control(bool),
+    /// Obstacle sensor active/inactive
+    Obstacle(bool),
+    /// Pedestrian input active/inactive
+    Pedestrian(bool),
+    /// Open limit switch of a door active/inactive
+    OpenLimit(Door, bool),
+    /// Close limit switch of a door active/inactive
+    CloseLimit(Door, bool),
+    /// Command sent to the FSM, as BLE would
+    Command(FsmCommand),
+    /// Command sent straight to the GPO task, bypassing the FSM sequencing
+    Gpo(GpoCommand),
+}
+
+/// Output timelines of a finished simulation
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    edges: [Vec<(u64, bool)>; 5],
+    states: Vec<StateChange>,
+}
+
+impl Trace {
+    /// State changes in the order the FSM published them, starting with its initial state
+    pub fn states(&self) -> &[StateChange] {
+        &self.states
+    }
+
+    /// Level changes of an output as (virtual ms, high), the pin starts low
+    pub fn edges(&self, relay: Relay) -> &[(u64, bool)] {
+        &self.edges[relay as usize]
+    }
+
+    /// Level changes as (on ms, off ms) intervals, `None` if still on at the end
+    pub fn intervals(&self, relay: Relay) -> Vec<(u64, Option<u64>)> {
+        let mut out = Vec::new();
+        for &(at, high) in self.edges(relay) {
+            if high {
+                out.push((at, None));
+            } else if let Some(last) = out.last_mut() {
+                last.1 = Some(at);
+            }
+        }
+        out
+    }
+
+    /// Level of an output at virtual time `at_ms`
+    pub fn is_high_at(&self, relay: Relay, at_ms: u64) -> bool {
+        self.edges(relay)
+            .iter()
+            .take_while(|(at, _)| *at <= at_ms)
+            .last()
+            .is_some_and(|(_, high)| *high)
+    }
+}
+
+/// Scripted run of the gate logic
+pub struct Simulation {
+    config: GateConfig,
+    input: GpiConfig,
+    output_polarity: u32,
+    relay_dead_time: Duration,
+    lamp: LampTimings,
+    pulse: PulseConfig,
+    script: Vec<(u64, Stimulus)>,
+}
+
+impl Simulation {
+    /// Inputs are active high, outputs use the default polarity and lamp timings
+    pub fn new(config: GateConfig) -> Self {
+        Self {
+            config,
+            // Control presses are reported as soon as they are accepted, obstacles at once
+            input: GpiConfig {
+                polarity: 0x7f,
+                long_press: Duration::from_ticks(0),
+                click_gap: Duration::from_ticks(0),
+                obstacle_detect_debounce: Duration::from_ticks(0),
+                ..GpiConfig::default()
+            },
+            output_polarity: 0,
+            relay_dead_time: Duration::from_millis(500),
+            lamp: LampTimings::default(),
+            pulse: PulseConfig::default(),
+            script: Vec::new(),
+        }
+    }
+
+    /// Polarity mask passed to the GPI loop
+    /// (bit 0 control, bit 1 obstacle, bit 2 pedestrian, bits 3..=6 limits; set = active high)
+    pub fn input_polarity(mut self, polarity: u32) -> Self {
+        self.input.polarity = polarity;
+        self
+    }
+
+    /// Debounce times passed to the GPI loop, the polarity and gesture timings are kept
+    pub fn input_debounce(mut self, config: GpiConfig) -> Self {
+        self.input = GpiConfig {
+            polarity: self.input.polarity,
+            long_press: self.input.long_press,
+            click_gap: self.input.click_gap,
+            ..config
+        };
+        self
+    }
+
+    /// Recognize control gestures (off by default, see `GpiConfig`)
+    pub fn control_gestures(mut self, long_press: Duration, click_gap: Duration) -> Self {
+        self.input.long_press = long_press;
+        self.input.click_gap = click_gap;
+        self
+    }
+
+    /// Polarity mask passed to the GPO loop (`gpo::POLARITY_*` bits set = active low)
+    pub fn output_polarity(mut self, polarity: u32) -> Self {
+        self.output_polarity = polarity;
+        self
+    }
+
+    /// Relay dead time passed to the GPO loop (500 ms by default)
+    pub fn relay_dead_time(mut self, dead_time: Duration) -> Self {
+        self.relay_dead_time = dead_time;
+        self
+    }
+
+    /// Lamp pattern timings passed to the GPO loop
+    pub fn lamp_timings(mut self, lamp: LampTimings) -> Self {
+        self.lamp = lamp;
+        self
+    }
+
+    /// Pulse mode relays and width passed to the GPO loop
+    pub fn pulse_config(mut self, pulse: PulseConfig) -> Self {
+        self.pulse = pulse;
+        self
+    }
+
+    /// Apply a stimulus at virtual time `at_ms`
+    pub fn at(mut self, at_ms: u64, stimulus: Stimulus) -> Self {
+        self.script.push((at_ms, stimulus));
+        self
+    }
+
+    /// Control input pulse of `PULSE_LENGTH_MS` starting at `at_ms`
+    pub fn pulse(self, at_ms: u64) -> Self {
+        self.at(at_ms, Stimulus::Control(true))
+            .at(at_ms + PULSE_LENGTH_MS, Stimulus::Control(false))
+    }
+
+    /// Pedestrian input pulse of `PULSE_LENGTH_MS` starting at `at_ms`
+    pub fn pedestrian_pulse(self, at_ms: u64) -> Self {
+        self.at(at_ms, Stimulus::Pedestrian(true))
+            .at(at_ms + PULSE_LENGTH_MS, Stimulus::Pedestrian(false))
+    }
+
+    /// Run for `duration_ms` of virtual time and return the output timelines
+    pub fn run(mut self, duration_ms: u64) -> Trace {
+        let _guard = SIM_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        self.script.sort_by_key(|(at, _)| *at);
+
+        let driver = MockDriver::get();
+        driver.reset();
+        GPI_CHANNEL.clear();
+        GPO_CHANNEL.clear();
+        FSM_COMMAND_CHANNEL.clear();
+        FAULT_EVENTS.clear();
+
+        let input_polarity = self.input.polarity;
+        let active_high = |bit: u32| input_polarity & bit != 0;
+        let control = MockInput::default();
+        let obstacle = MockInput::default();
+        let pedestrian = MockInput::default();
+        control.set_high(!active_high(1));
+        obstacle.set_high(!active_high(2));
+        pedestrian.set_high(!active_high(4));
+        // Left open, left close, right open, right close, as `run_gpi` takes them
+        let limits: [MockInput; 4] = Default::default();
+        for (i, limit) in limits.iter().enumerate() {
+            limit.set_high(!active_high(8 << i));
+        }
+        let limit_index = |door: Door, close: bool| match door {
+            Door::Left => close as usize,
+            Door::Right => 2 + close as usize,
+        };
+        let outputs: [MockOutput; 5] = Default::default();
+        let mut state_events = subscribe_state().expect("state subscriber slot");
+        let mut states = Vec::new();
+
+        let tasks = join3(
+            run_fsm(self.config),
+            run_gpi(
+                control.clone(),
+                obstacle.clone(),
+                Some(pedestrian.clone()),
+                Some(limits.clone()),
+                self.input,
+            ),
+            run_gpo(
+                outputs[Relay::Lamp as usize].clone(),
+                outputs[Relay::LeftOpen as usize].clone(),
+                outputs[Relay::LeftClose as usize].clone(),
+                outputs[Relay::RightOpen as usize].clone(),
+                outputs[Relay::RightClose as usize].clone(),
+                GpoConfig {
+                    polarity: self.output_polarity,
+                    relay_dead_time: self.relay_dead_time,
+                    lamp: self.lamp,
+                    pulse: self.pulse,
+                },
+            ),
+        );
+
+        let script = async {
+            let mut steps = self.script.iter().peekable();
+            for now in 0..=duration_ms {
+                while let Some((_, stimulus)) = steps.next_if(|(at, _)| *at <= now) {
+                    match *stimulus {
+                        Stimulus::Control(active) => control.set_high(active == active_high(1)),
+                        Stimulus::Obstacle(active) => obstacle.set_high(active == active_high(2)),
+                        Stimulus::Pedestrian(active) => pedestrian.set_high(active == active_high(4)),
+                        Stimulus::OpenLimit(door, active) | Stimulus::CloseLimit(door, active) => {
+                            let i = limit_index(door, matches!(stimulus, Stimulus::CloseLimit(..)));
+                            limits[i].set_high(active == active_high(8 << i));
+                        }
+                        Stimulus::Command(cmd) => {
+                            if FSM_COMMAND_CHANNEL.try_send(cmd).is_err() {
+                                panic!("FSM command channel full at {} ms", now);
+                            }
+                        }
+                        Stimulus::Gpo(cmd) => {
+                            if GPO_CHANNEL.try_send(cmd).is_err() {
+                                panic!("GPO command channel full at {} ms", now);
+                            }
+                        }
+                    }
+                }
+                for _ in 0..SETTLE_POLLS {
+                    yield_now().await;
+                }
+                while let Some(change) = state_events.try_next_message_pure() {
+                    states.push(change);
+                }
+                if now < duration_ms {
+                    driver.advance(Duration::from_millis(1));
+                }
+            }
+        };
+
+        embassy_futures::block_on(select(tasks, script));
+
+        Trace {
+            edges: outputs.map(|o| o.edges()),
+            states,
+        }
+    }
+}
@@ -1,6 +1,6 @@
 //! Common types, events, and commands for the gate controller
 
-use embassy_time::Duration;
+use embassy_time::{Duration, Instant};
 
 /// Door identifier
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -10,7 +10,7 @@ pub enum Door {
 }
 
 /// Commands that can be sent to the FSM from external processes
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FsmCommand {
     /// Start opening sequence
     Open,
@@ -18,17 +18,112 @@ pub enum FsmCommand {
     Close,
     /// Temporarily disable autoclose (re-enabled on next open cycle)
     StopAutoClose,
+    /// Halt the doors where they are
+    Stop,
+    /// Open only the pedestrian leaf, partially (`GateConfig::pedestrian_door`)
+    OpenPedestrian,
+    /// Leave the Fault state (admin action)
+    ClearFault,
 }
 
-/// Events generated by the GPI (input) process
+/// Event generated by the GPI (input) process
 #[derive(Debug, Clone, Copy)]
-pub enum GpiEvent {
-    /// Control input pulse detected (debounced)
-    ControlPulse,
+pub struct GpiEvent {
+    pub kind: GpiEventKind,
+    /// When the input changed; for repeated obstacle reports, when the obstacle appeared
+    pub at: Instant,
+}
+
+/// Input changes reported by the GPI process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpiEventKind {
+    /// Control input gesture recognized (debounced)
+    Control(ControlGesture),
     /// Obstacle detected (debounced, active)
     ObstacleDetected,
     /// Obstacle cleared (debounced, inactive)
     ObstacleCleared,
+    /// Pedestrian input pulse detected (debounced)
+    PedestrianPulse,
+    /// Left door open limit switch tripped (debounced)
+    LeftOpenLimit,
+    /// Left door close limit switch tripped (debounced)
+    LeftCloseLimit,
+    /// Right door open limit switch tripped (debounced)
+    RightOpenLimit,
+    /// Right door close limit switch tripped (debounced)
+    RightCloseLimit,
+}
+
+impl GpiEventKind {
+    /// Door and direction whose end position a limit event reports
+    pub fn limit(self) -> Option<(Door, DoorMovement)> {
+        match self {
+            GpiEventKind::LeftOpenLimit => Some((Door::Left, DoorMovement::Opening)),
+            GpiEventKind::LeftCloseLimit => Some((Door::Left, DoorMovement::Closing)),
+            GpiEventKind::RightOpenLimit => Some((Door::Right, DoorMovement::Opening)),
+            GpiEventKind::RightCloseLimit => Some((Door::Right, DoorMovement::Closing)),
+            _ => None,
+        }
+    }
+}
+
+/// Input settings, fixed when the GPI task starts
+#[derive(Debug, Clone, Copy)]
+pub struct GpiConfig {
+    /// Input polarity bitmap, bits set = active high:
+    /// 0 control, 1 obstacle, 2 pedestrian, 3..=6 limit switches
+    pub polarity: u32,
+    /// Time a new level must hold before it is accepted, per input
+    pub control_debounce: Duration,
+    /// Control input held this long is a long press, 0 = no long presses
+    pub long_press: Duration,
+    /// Longest gap between the clicks of a double/triple click, 0 = single clicks only
+    /// With both 0 a press is reported as soon as it is accepted
+    pub click_gap: Duration,
+    /// Time an obstacle must be present before it is reported: kept short so the
+    /// doors stop quickly, 0 reports every spike on the sensor line
+    pub obstacle_detect_debounce: Duration,
+    /// Time the obstacle input must stay clear before the obstacle is cleared
+    pub obstacle_debounce: Duration,
+    pub pedestrian_debounce: Duration,
+    pub limit_debounce: Duration,
+}
+
+impl Default for GpiConfig {
+    fn default() -> Self {
+        Self {
+            polarity: 0,
+            control_debounce: Duration::from_millis(100),
+            long_press: Duration::from_secs(2),
+            click_gap: Duration::from_millis(500),
+            obstacle_detect_debounce: Duration::from_millis(20),
+            obstacle_debounce: Duration::from_millis(100),
+            pedestrian_debounce: Duration::from_millis(100),
+            limit_debounce: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Snapshot of the debounced input levels, bits as in `GpiConfig::polarity`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InputLevels {
+    /// Inputs currently active
+    pub active: u8,
+    /// Inputs monitored by the GPI task (pedestrian and limit switches are optional)
+    pub fitted: u8,
+}
+
+impl InputLevels {
+    pub const CONTROL: u8 = 1 << 0;
+    pub const OBSTACLE: u8 = 1 << 1;
+    pub const PEDESTRIAN: u8 = 1 << 2;
+    /// Left open, left close, right open, right close
+    pub const LIMITS: [u8; 4] = [1 << 3, 1 << 4, 1 << 5, 1 << 6];
+
+    pub fn is_active(&self, input: u8) -> bool {
+        self.active & input != 0
+    }
 }
 
 /// Commands sent to the GPO (output) process
@@ -40,15 +135,62 @@ pub enum GpoCommand {
     SetDoorClose { door: Door, active: bool },
     /// Set signal lamp state
     SetLamp(LampState),
+    /// Pulse the relay configured for a third-party controller command
+    Pulse(PulseCommand),
 }
 
-/// Signal lamp states
+/// Signal lamp states, each one a pattern timed by `LampTimings`
+/// Patterns start from their first on phase whenever the state changes
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum LampState {
     /// Lamp off
     Off,
-    /// Blinking at 1/2 Hz with 75% duty cycle (1.5s on, 0.5s off)
+    /// Lamp held on
+    Steady,
+    /// Blinking while the doors move (`blink_on`/`blink_off`)
     Blinking,
+    /// Fast blinking during the lamp prestart (`prestart_on`/`prestart_off`)
+    Prestart,
+    /// Fault code as a repeating sequence of `FaultCode::flashes` flashes
+    Fault(FaultCode),
+}
+
+/// Lamp pattern timings
+/// A zero off time holds the lamp on, a zero on time keeps it off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LampTimings {
+    pub blink_on: Duration,
+    pub blink_off: Duration,
+    pub prestart_on: Duration,
+    pub prestart_off: Duration,
+    /// On and off time of each fault code flash
+    pub fault_flash: Duration,
+    /// Off time between fault code sequences
+    pub fault_pause: Duration,
+}
+
+impl Default for LampTimings {
+    fn default() -> Self {
+        Self {
+            blink_on: Duration::from_millis(1500),
+            blink_off: Duration::from_millis(500),
+            prestart_on: Duration::from_millis(250),
+            prestart_off: Duration::from_millis(250),
+            fault_flash: Duration::from_millis(250),
+            fault_pause: Duration::from_millis(1500),
+        }
+    }
+}
+
+/// Output settings, fixed when the GPO task starts
+#[derive(Debug, Clone, Copy)]
+pub struct GpoConfig {
+    /// Output polarity bitmap, `gpo::POLARITY_*` bits set = active low
+    pub polarity: u32,
+    /// Both relays of a door stay off for this long when it changes direction
+    pub relay_dead_time: Duration,
+    pub lamp: LampTimings,
+    pub pulse: PulseConfig,
 }
 
 /// FSM states for the gate controller
@@ -58,6 +200,341 @@ pub enum GateState {
     Opening,
     Open,
     Closing,
+    /// Halted by a stop request; `partial` is set when the doors are between
+    /// end positions, clear when the gate is held fully open
+    Stopped { partial: bool },
+    /// Relays off after a failure; left only by `FsmCommand::ClearFault`
+    Fault(FaultCode),
+}
+
+/// Reason for the Fault state
+/// The value is reported over BLE, stored in the fault history and flashed by the lamp
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultCode {
+    /// A door did not reach its limit switch within its open/close duration
+    TravelTimeout = 1,
+    /// Obstacle input active for longer than `GateConfig::obstacle_stuck_after`
+    SensorStuck = 2,
+    /// Open and close relays of a door requested together
+    RelayInterlock = 3,
+    /// Gate configuration out of range (see `GateConfig::validate`)
+    ConfigInvalid = 4,
+}
+
+impl FaultCode {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(FaultCode::TravelTimeout),
+            2 => Some(FaultCode::SensorStuck),
+            3 => Some(FaultCode::RelayInterlock),
+            4 => Some(FaultCode::ConfigInvalid),
+            _ => None,
+        }
+    }
+
+    /// Number of lamp flashes per sequence
+    pub fn flashes(self) -> u8 {
+        self as u8
+    }
+}
+
+/// A fault raised by the FSM, for the fault history
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FaultEvent {
+    pub code: FaultCode,
+    pub at: Instant,
+}
+
+/// Gestures recognized on the control input
+/// The press is reported once it can no longer become another gesture
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlGesture {
+    ShortPress,
+    /// Held for `GpiConfig::long_press`, reported while still held
+    LongPress,
+    DoubleClick,
+    TripleClick,
+}
+
+/// What a control gesture does, configured per gesture in `GateConfig::control_actions`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlAction {
+    /// Gesture is ignored
+    None,
+    /// Control pulse, interpreted according to `ControlMode`
+    Pulse,
+    /// Command handled as if sent over BLE
+    Command(FsmCommand),
+}
+
+impl ControlAction {
+    /// Decode a gesture action config slot: 0 none, 1 control pulse, 2 open,
+    /// 3 close, 4 stop, 5 pedestrian open, 6 stop autoclose; unknown values are none
+    pub fn from_config(value: u32) -> Self {
+        match value {
+            1 => ControlAction::Pulse,
+            2 => ControlAction::Command(FsmCommand::Open),
+            3 => ControlAction::Command(FsmCommand::Close),
+            4 => ControlAction::Command(FsmCommand::Stop),
+            5 => ControlAction::Command(FsmCommand::OpenPedestrian),
+            6 => ControlAction::Command(FsmCommand::StopAutoClose),
+            _ => ControlAction::None,
+        }
+    }
+}
+
+/// How a control pulse is interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlMode {
+    /// Pulse opens; while open it restarts autoclose; ignored while moving
+    Standard,
+    /// Each pulse steps open -> stop -> close -> stop
+    StepByStep,
+}
+
+impl ControlMode {
+    /// Decode the `ControlMode` config slot, unknown values fall back to Standard
+    pub fn from_config(value: u32) -> Self {
+        match value {
+            1 => ControlMode::StepByStep,
+            _ => ControlMode::Standard,
+        }
+    }
+}
+
+/// How the FSM drives the gate
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriveMode {
+    /// Door relays timed by the FSM (delays, durations, obstacle reversal)
+    Timed,
+    /// Open/close/stop become relay pulses for a third-party gate controller,
+    /// which times the doors itself
+    Pulse,
+}
+
+impl DriveMode {
+    /// Decode the `DriveMode` config slot, unknown values fall back to Timed
+    pub fn from_config(value: u32) -> Self {
+        match value {
+            1 => DriveMode::Pulse,
+            _ => DriveMode::Timed,
+        }
+    }
+}
+
+/// Commands understood by a third-party gate controller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PulseCommand {
+    Open,
+    Close,
+    Stop,
+}
+
+/// Door relay outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelayOutput {
+    LeftOpen,
+    LeftClose,
+    RightOpen,
+    RightClose,
+}
+
+impl RelayOutput {
+    /// Decode a pulse relay config slot: 0 left open, 1 left close,
+    /// 2 right open, 3 right close, anything else none
+    pub fn from_config(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(RelayOutput::LeftOpen),
+            1 => Some(RelayOutput::LeftClose),
+            2 => Some(RelayOutput::RightOpen),
+            3 => Some(RelayOutput::RightClose),
+            _ => None,
+        }
+    }
+
+    pub fn door(self) -> Door {
+        match self {
+            RelayOutput::LeftOpen | RelayOutput::LeftClose => Door::Left,
+            RelayOutput::RightOpen | RelayOutput::RightClose => Door::Right,
+        }
+    }
+
+    pub fn movement(self) -> DoorMovement {
+        match self {
+            RelayOutput::LeftOpen | RelayOutput::RightOpen => DoorMovement::Opening,
+            RelayOutput::LeftClose | RelayOutput::RightClose => DoorMovement::Closing,
+        }
+    }
+}
+
+/// Pulse mode outputs: relay per command (None: not wired) and pulse width
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PulseConfig {
+    pub width: Duration,
+    pub open: Option<RelayOutput>,
+    pub close: Option<RelayOutput>,
+    pub stop: Option<RelayOutput>,
+}
+
+impl PulseConfig {
+    pub fn relay(&self, command: PulseCommand) -> Option<RelayOutput> {
+        match command {
+            PulseCommand::Open => self.open,
+            PulseCommand::Close => self.close,
+            PulseCommand::Stop => self.stop,
+        }
+    }
+}
+
+impl Default for PulseConfig {
+    fn default() -> Self {
+        Self {
+            width: Duration::from_millis(500),
+            open: Some(RelayOutput::LeftOpen),
+            close: Some(RelayOutput::LeftClose),
+            stop: Some(RelayOutput::RightOpen),
+        }
+    }
+}
+
+/// Relay currently driven for a door
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DoorMovement {
+    Stopped,
+    Opening,
+    Closing,
+}
+
+/// Door position scale: 0 = closed, `POSITION_OPEN` = fully open (per mille of travel)
+pub const POSITION_OPEN: u16 = 1000;
+
+/// Movement and estimated position of a single door
+/// The position is estimated from relay-on time against the configured
+/// open/close durations; limit switches, when fitted, pin the end positions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DoorStatus {
+    /// Relay currently driven
+    pub movement: DoorMovement,
+    /// Estimated position when `movement` started
+    pub position: u16,
+    /// When `movement` started
+    pub since: Instant,
+    /// Duration of a full travel in the direction of `movement`
+    pub full_travel: Duration,
+}
+
+impl DoorStatus {
+    /// Stopped in the closed position
+    pub const fn closed() -> Self {
+        Self {
+            movement: DoorMovement::Stopped,
+            position: 0,
+            since: Instant::from_ticks(0),
+            full_travel: Duration::from_ticks(0),
+        }
+    }
+
+    /// Estimated position at `now`
+    pub fn position_at(&self, now: Instant) -> u16 {
+        let elapsed = now.saturating_duration_since(self.since).as_ticks();
+        let full = self.full_travel.as_ticks().max(1);
+        let moved = (elapsed * POSITION_OPEN as u64 / full).min(POSITION_OPEN as u64) as u16;
+        match self.movement {
+            DoorMovement::Stopped => self.position,
+            DoorMovement::Opening => (self.position + moved).min(POSITION_OPEN),
+            DoorMovement::Closing => self.position.saturating_sub(moved),
+        }
+    }
+
+    /// Relay-on time needed to reach `target` moving in `movement` direction from the
+    /// position at `now`; zero if the door is already at or past `target`
+    pub fn remaining(&self, movement: DoorMovement, target: u16, full_travel: Duration, now: Instant) -> Duration {
+        let position = self.position_at(now);
+        let distance = match movement {
+            DoorMovement::Stopped => 0,
+            DoorMovement::Opening => target.saturating_sub(position),
+            DoorMovement::Closing => position.saturating_sub(target),
+        };
+        Duration::from_ticks(full_travel.as_ticks() * distance as u64 / POSITION_OPEN as u64)
+    }
+
+    /// Start moving from the position reached at `now`
+    pub fn start(&mut self, movement: DoorMovement, full_travel: Duration, now: Instant) {
+        self.position = self.position_at(now);
+        self.movement = movement;
+        self.since = now;
+        self.full_travel = full_travel;
+    }
+
+    /// Stop, keeping the estimated position reached at `now`
+    pub fn stop(&mut self, now: Instant) {
+        self.start(DoorMovement::Stopped, Duration::from_ticks(0), now);
+    }
+
+    /// Stop at a known position (end of a complete travel)
+    pub fn stop_at(&mut self, position: u16, now: Instant) {
+        self.stop(now);
+        self.position = position;
+    }
+}
+
+/// Snapshot of the gate published by the FSM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GateStatus {
+    pub state: GateState,
+    pub left: DoorStatus,
+    pub right: DoorStatus,
+    /// Last debounced obstacle input level
+    pub obstacle: bool,
+    /// When the obstacle input last became active
+    pub obstacle_since: Instant,
+    /// When autoclose will start closing, None if the timer is not running
+    pub autoclose_at: Option<Instant>,
+    /// When the gate entered `state`
+    pub since: Instant,
+}
+
+/// Gate state transition published by the FSM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StateChange {
+    pub from: GateState,
+    pub to: GateState,
+    pub at: Instant,
+}
+
+impl GateStatus {
+    pub const fn new() -> Self {
+        Self {
+            state: GateState::Closed,
+            left: DoorStatus::closed(),
+            right: DoorStatus::closed(),
+            obstacle: false,
+            obstacle_since: Instant::from_ticks(0),
+            autoclose_at: None,
+            since: Instant::from_ticks(0),
+        }
+    }
+
+    pub fn door(&self, door: Door) -> &DoorStatus {
+        match door {
+            Door::Left => &self.left,
+            Door::Right => &self.right,
+        }
+    }
+
+    pub fn door_mut(&mut self, door: Door) -> &mut DoorStatus {
+        match door {
+            Door::Left => &mut self.left,
+            Door::Right => &mut self.right,
+        }
+    }
+}
+
+impl Default for GateStatus {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 /// Configuration for a single door
@@ -89,6 +566,18 @@ impl DoorConfig {
     }
 }
 
+/// Gesture actions used when not configured: short press, long press and
+/// double click are control pulses, a triple click closes
+pub const DEFAULT_CONTROL_ACTIONS: [ControlAction; 4] = [
+    ControlAction::Pulse,
+    ControlAction::Pulse,
+    ControlAction::Pulse,
+    ControlAction::Command(FsmCommand::Close),
+];
+
+/// Longest accepted open/close duration
+pub const MAX_TRAVEL: Duration = Duration::from_secs(600);
+
 /// Configuration for the entire gate system
 #[derive(Debug, Clone, Copy)]
 pub struct GateConfig {
@@ -98,6 +587,55 @@ pub struct GateConfig {
     pub autoclose_delay: Option<Duration>,
     /// How long before door movement the lamp should start blinking
     pub lamp_prestart: Duration,
+    /// Control pulse behaviour
+    pub control_mode: ControlMode,
+    /// Action of each control gesture, indexed by `ControlGesture`
+    pub control_actions: [ControlAction; 4],
+    /// Leaf opened by `OpenPedestrian`
+    pub pedestrian_door: Door,
+    /// Open relay time of the pedestrian leaf, from closed
+    pub pedestrian_travel: Duration,
+    /// Doors have open/close limit switches: end-position moves run until the
+    /// switch trips, open/close durations only bound the travel
+    pub limit_switches: bool,
+    /// A close started while the obstacle input has been active for longer than
+    /// this raises `FaultCode::SensorStuck`; None disables the check
+    pub obstacle_stuck_after: Option<Duration>,
+    /// Timed door relays, or pulses for a third-party controller
+    pub drive_mode: DriveMode,
+}
+
+impl GateConfig {
+    pub fn door(&self, door: Door) -> &DoorConfig {
+        match door {
+            Door::Left => &self.left_door,
+            Door::Right => &self.right_door,
+        }
+    }
+
+    /// Check the timings are usable: every travel between zero and `MAX_TRAVEL`
+    /// Pulse mode does not time the doors, so there is nothing to check
+    pub fn validate(&self) -> Result<(), FaultCode> {
+        if self.drive_mode == DriveMode::Pulse {
+            return Ok(());
+        }
+        let travel_ok = |d: Duration| d.as_ticks() > 0 && d <= MAX_TRAVEL;
+        let doors_ok = [&self.left_door, &self.right_door]
+            .iter()
+            .all(|door| travel_ok(door.open_duration) && travel_ok(door.close_duration));
+        if doors_ok && travel_ok(self.pedestrian_travel) {
+            Ok(())
+        } else {
+            Err(FaultCode::ConfigInvalid)
+        }
+    }
+
+    /// Position the pedestrian leaf is opened to
+    pub fn pedestrian_position(&self) -> u16 {
+        let full = self.door(self.pedestrian_door).open_duration.as_ticks().max(1);
+        let travel = self.pedestrian_travel.as_ticks().min(full);
+        (travel * POSITION_OPEN as u64 / full) as u16
+    }
 }
 
 impl Default for GateConfig {
@@ -117,6 +655,13 @@ impl Default for GateConfig {
             ),
             autoclose_delay: Some(Duration::from_secs(30)),
             lamp_prestart: Duration::from_secs(1),
+            control_mode: ControlMode::Standard,
+            control_actions: DEFAULT_CONTROL_ACTIONS,
+            pedestrian_door: Door::Left,
+            pedestrian_travel: Duration::from_secs(5),
+            limit_switches: false,
+            obstacle_stuck_after: Some(Duration::from_secs(300)),
+            drive_mode: DriveMode::Timed,
         }
     }
 }
@@ -0,0 +1,120 @@
+//! Auth log ring buffer on the simulated flash
+//!
+//! cargo +stable simtest
+#![cfg(feature = "std")]
+
+use blue_gate::authlog::{AuthLog, AuthLogEntry, MAX_CAPACITY};
+use blue_gate::clock::DateTime;
+use blue_gate::sim::MockFlash;
+use embassy_futures::block_on;
+use sequential_storage::cache::NoCache;
+use sequential_storage::queue;
+
+/// Auth log flash range of the firmware
+const LOG_RANGE: core::ops::Range<u32> = 0x3D6000..0x3DE000;
+
+/// Entry `n`, told apart by its uptime
+fn entry(n: u64) -> AuthLogEntry {
+    AuthLogEntry {
+        pubkey: [n as u8; 33],
+        uptime_ms: n,
+        time: Some(DateTime::from_unix(1_700_000_000 + n)),
+        addr: [1, 2, 3, 4, 5, 6],
+        auth_action: 1,
+        success: n.is_multiple_of(2),
+        result: Some(if n.is_multiple_of(2) { 0 } else { 1 }),
+    }
+}
+
+/// Uptimes of all entries, newest first
+fn uptimes(log: &mut AuthLog, flash: &mut MockFlash) -> Vec<u64> {
+    (0..log.count()).map(|i| block_on(log.get(flash, i)).unwrap().uptime_ms).collect()
+}
+
+#[test]
+fn the_oldest_entry_is_dropped_once_full() {
+    let mut flash = MockFlash::default();
+    let mut log = block_on(AuthLog::new(&mut flash, 3));
+    for n in 0..5 {
+        block_on(log.push(&mut flash, entry(n))).unwrap();
+    }
+    assert_eq!(log.count(), 3);
+    assert_eq!(uptimes(&mut log, &mut flash), [4, 3, 2]);
+    assert!(block_on(log.get(&mut flash, 3)).is_none());
+    assert_eq!(block_on(log.entry_bytes(&mut flash, 3)), [0; 55]);
+
+    // Index 0 is the newest, also across a restart
+    let newest = block_on(log.get(&mut flash, 0)).unwrap();
+    assert_eq!(newest.to_bytes(), entry(4).to_bytes());
+    let mut log = block_on(AuthLog::new(&mut flash, 3));
+    assert_eq!(uptimes(&mut log, &mut flash), [4, 3, 2]);
+}
+
+#[test]
+fn lowering_the_capacity_drops_the_oldest_entries() {
+    let mut flash = MockFlash::default();
+    let mut log = block_on(AuthLog::new(&mut flash, 10));
+    for n in 0..10 {
+        block_on(log.push(&mut flash, entry(n))).unwrap();
+    }
+    let mut log = block_on(AuthLog::new(&mut flash, 4));
+    assert_eq!(uptimes(&mut log, &mut flash), [9, 8, 7, 6]);
+    // Dropped from flash, raising it again does not bring them back
+    let mut log = block_on(AuthLog::new(&mut flash, 10));
+    assert_eq!(uptimes(&mut log, &mut flash), [9, 8, 7, 6]);
+
+    // A capacity of 0 keeps one entry
+    let mut log = block_on(AuthLog::new(&mut flash, 0));
+    block_on(log.push(&mut flash, entry(10))).unwrap();
+    assert_eq!(uptimes(&mut log, &mut flash), [10]);
+}
+
+#[test]
+fn entries_of_earlier_firmware_are_read() {
+    let mut flash = MockFlash::default();
+    let mut cache = NoCache::new();
+    // v1 without the time, v2 without the result code
+    let v1 = entry(1).to_bytes();
+    let v2 = entry(2).to_bytes();
+    block_on(queue::push(&mut flash, LOG_RANGE, &mut cache, &v1[..50], false)).unwrap();
+    block_on(queue::push(&mut flash, LOG_RANGE, &mut cache, &v2[..54], false)).unwrap();
+
+    let mut log = block_on(AuthLog::new(&mut flash, 10));
+    assert_eq!(log.count(), 2);
+    let v1 = block_on(log.get(&mut flash, 1)).unwrap();
+    assert_eq!(v1.pubkey, [1; 33]);
+    assert_eq!(v1.uptime_ms, 1);
+    assert_eq!(v1.addr, [1, 2, 3, 4, 5, 6]);
+    assert!(!v1.success);
+    assert!(v1.time.is_none());
+    assert_eq!(v1.result, None);
+    // Read back as unsynced, with an unknown result
+    assert_eq!(v1.to_bytes()[0], 0x01 | 0x04);
+    assert_eq!(v1.to_bytes()[54], 0xFF);
+
+    let v2 = block_on(log.get(&mut flash, 0)).unwrap();
+    assert_eq!(v2.time, Some(DateTime::from_unix(1_700_000_002)));
+    assert!(v2.success);
+    assert_eq!(v2.result, None);
+}
+
+#[test]
+fn paging_through_a_full_log_does_not_walk_the_queue_per_entry() {
+    let mut flash = MockFlash::default();
+    let mut log = block_on(AuthLog::new(&mut flash, MAX_CAPACITY));
+    for n in 0..MAX_CAPACITY as u64 {
+        block_on(log.push(&mut flash, entry(n))).unwrap();
+    }
+    let before = flash.bytes_read();
+    let expected: Vec<u64> = (0..MAX_CAPACITY as u64).rev().collect();
+    assert_eq!(uptimes(&mut log, &mut flash), expected);
+    // A walk per entry reads ~200 entries of 64 bytes each time, ~5MB in total
+    let read = flash.bytes_read() - before;
+    assert!(read < 1_000_000, "{} bytes read", read);
+
+    // A new entry is the new index 0
+    block_on(log.push(&mut flash, entry(1_000))).unwrap();
+    assert_eq!(block_on(log.get(&mut flash, 0)).unwrap().uptime_ms, 1_000);
+    assert_eq!(block_on(log.get(&mut flash, 1)).unwrap().uptime_ms, MAX_CAPACITY as u64 - 1);
+    assert_eq!(block_on(log.get(&mut flash, MAX_CAPACITY - 1)).unwrap().uptime_ms, 1);
+}
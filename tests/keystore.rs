@@ -0,0 +1,405 @@
+//! Key store persistence on the simulated flash
+//!
+//! cargo +stable simtest
+#![cfg(feature = "std")]
+
+use blue_gate::keys::{fingerprint, AccessDenied, KeyInfo, KeyStore, Validity, LABEL_LEN, STORE_KEYS, UNLIMITED_USES};
+use blue_gate::schedule::{self, Schedule, ScheduleStore, Window};
+use blue_gate::sim::MockFlash;
+use embassy_futures::block_on;
+use sequential_storage::cache::NoCache;
+use sequential_storage::map;
+
+/// Key store flash range of the firmware
+const KEY_RANGE: core::ops::Range<u32> = 0x3E0000..0x3F0000;
+
+/// Admin key `n`: permission bits set, key type 1
+fn key(n: u8) -> [u8; 33] {
+    let mut key = [n; 33];
+    key[0] = 0xfc | 0x01;
+    key
+}
+
+/// Keys as loaded by a restart
+fn reload(flash: &MockFlash) -> Vec<[u8; 33]> {
+    let (store, _) = block_on(KeyStore::new(flash.clone()));
+    (0..store.len()).map(|i| *store.get(i).unwrap()).collect()
+}
+
+/// Schedule store without any profile
+fn no_schedules() -> ScheduleStore {
+    block_on(ScheduleStore::new(MockFlash::default())).0
+}
+
+/// Small xorshift generator so the cut offsets are reproducible
+struct Rng(u32);
+
+impl Rng {
+    fn below(&mut self, n: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        self.0 as usize % n
+    }
+}
+
+#[test]
+fn keys_survive_a_restart() {
+    let mut flash = MockFlash::default();
+    let (mut store, _) = block_on(KeyStore::new(&mut flash));
+    for n in 1..=3 {
+        assert!(block_on(store.add(&mut flash, key(n), KeyInfo::default())).unwrap());
+    }
+    assert!(block_on(store.del(&mut flash, key(2))).unwrap());
+    // The freed slot is reused
+    assert!(block_on(store.add(&mut flash, key(4), KeyInfo::default())).unwrap());
+    assert!(!block_on(store.add(&mut flash, key(4), KeyInfo::default())).unwrap());
+
+    assert_eq!(reload(&flash), [key(1), key(4), key(3)]);
+}
+
+#[test]
+fn an_edit_does_not_rewrite_the_store() {
+    let mut flash = MockFlash::default();
+    let (mut store, _) = block_on(KeyStore::new(&mut flash));
+    for n in 1..=100 {
+        block_on(store.add(&mut flash, key(n), KeyInfo::default())).unwrap();
+    }
+    let before = flash.bytes_written();
+    block_on(store.del(&mut flash, key(50))).unwrap();
+    block_on(store.add(&mut flash, key(101), KeyInfo::default())).unwrap();
+    assert!(flash.bytes_written() - before < 128, "{} bytes written", flash.bytes_written() - before);
+}
+
+#[test]
+fn a_full_store_can_still_be_edited_and_shrunk() {
+    let wide_key = |n: usize| {
+        let mut key = key(0);
+        key[1..3].copy_from_slice(&(n as u16).to_le_bytes());
+        key
+    };
+    let mut flash = MockFlash::default();
+    let (mut store, _) = block_on(KeyStore::new(&mut flash));
+    for n in 0..STORE_KEYS {
+        assert!(block_on(store.add(&mut flash, wide_key(n), KeyInfo::default())).unwrap(), "key {}", n);
+    }
+    assert!(!block_on(store.add(&mut flash, wide_key(STORE_KEYS), KeyInfo::default())).unwrap());
+
+    // Every key limited and used, then edited: the key range does not fill up
+    let week = Validity { not_before: 1_700_000_000, not_after: 1_700_604_800 };
+    for n in 0..STORE_KEYS {
+        assert!(block_on(store.set_uses(&mut flash, wide_key(n), 5)).unwrap());
+        assert_eq!(block_on(store.record_use(&mut flash, wide_key(n))).unwrap(), Some(4));
+        assert!(block_on(store.set_validity(&mut flash, wide_key(n), week)).unwrap());
+    }
+    assert!(block_on(store.del(&mut flash, wide_key(7))).unwrap());
+    assert!(block_on(store.del(&mut flash, wide_key(8))).unwrap());
+    assert!(block_on(store.add(&mut flash, wide_key(STORE_KEYS), KeyInfo::default())).unwrap());
+
+    let (store, _) = block_on(KeyStore::new(flash.clone()));
+    assert_eq!(store.len(), STORE_KEYS - 1);
+    assert_eq!(store.uses(&wide_key(9)), Some(4));
+    assert_eq!(store.validity(&wide_key(9)), Some(week));
+    // The new key took the ID of a deleted one, not its counter
+    assert_eq!(store.uses(&wide_key(STORE_KEYS)), Some(UNLIMITED_USES));
+    assert_eq!(store.lookup(&wide_key(7)), 0);
+}
+
+#[test]
+fn keys_of_the_33_byte_layout_load_without_a_window() {
+    let mut flash = MockFlash::default();
+    let mut buf = [0u8; 64];
+    for (id, n) in [(1u16, 1), (2, 2)] {
+        block_on(map::store_item::<u16, [u8; 33], _>(&mut flash, KEY_RANGE, &mut NoCache::new(), &mut buf, &id, &key(n)))
+            .unwrap();
+    }
+
+    let (mut store, _) = block_on(KeyStore::new(&mut flash));
+    assert_eq!(store.len(), 2);
+    assert_eq!(store.validity(&key(1)), Some(Validity::ALWAYS));
+    assert_eq!(store.authorize(&key(1), None, &no_schedules()), Ok(key(1)[0]));
+
+    // The edited key is rewritten in the new layout, the other one stays as it was
+    let week = Validity { not_before: 1_700_000_000, not_after: 1_700_604_800 };
+    assert!(block_on(store.set_validity(&mut flash, key(2), week)).unwrap());
+    assert!(block_on(store.add(&mut flash, key(3), KeyInfo::default())).unwrap());
+    let (store, _) = block_on(KeyStore::new(flash.clone()));
+    assert_eq!(reload(&flash), [key(1), key(2), key(3)]);
+    assert_eq!(store.validity(&key(1)), Some(Validity::ALWAYS));
+    assert_eq!(store.validity(&key(2)), Some(week));
+}
+
+#[test]
+fn a_store_in_the_baseline_layout_is_migrated() {
+    // The old firmware kept a key count under ID 0 and rewrote keys 1..=count on
+    // every edit: three keys added, then the second deleted, which shifted the
+    // third down and left its old record behind past the count
+    let mut flash = MockFlash::default();
+    let mut save = |count: u16, keys: &[u8]| {
+        let mut buf = [0u8; 64];
+        block_on(map::store_item::<u16, u16, _>(&mut flash, KEY_RANGE, &mut NoCache::new(), &mut buf, &0, &count)).unwrap();
+        for (id, &n) in (1u16..).zip(keys) {
+            block_on(map::store_item::<u16, [u8; 33], _>(&mut flash, KEY_RANGE, &mut NoCache::new(), &mut buf, &id, &key(n)))
+                .unwrap();
+        }
+    };
+    save(3, &[1, 2, 3]);
+    save(2, &[1, 3]);
+
+    assert_eq!(reload(&flash), [key(1), key(3)]);
+    let (mut store, _) = block_on(KeyStore::new(&mut flash));
+    assert_eq!(store.len(), 2);
+    // The stale record is tombstoned and the count marked, so the next start does not migrate again
+    let fetch = |flash: &mut MockFlash, id: u16| {
+        let mut buf = [0u8; 64];
+        block_on(map::fetch_item::<u16, &[u8], _>(flash, KEY_RANGE, &mut NoCache::new(), &mut buf, &id))
+            .unwrap()
+            .map(<[u8]>::to_vec)
+    };
+    assert_eq!(fetch(&mut flash, 3), Some(Vec::new()));
+    assert_eq!(fetch(&mut flash, 0), Some(u16::MAX.to_le_bytes().to_vec()));
+    let before = flash.bytes_written();
+    assert_eq!(reload(&flash), [key(1), key(3)]);
+    block_on(KeyStore::new(&mut flash));
+    assert_eq!(flash.bytes_written(), before);
+
+    // The freed ID is reused by the next key
+    assert!(block_on(store.add(&mut flash, key(4), KeyInfo::default())).unwrap());
+    assert_eq!(reload(&flash), [key(1), key(3), key(4)]);
+}
+
+#[test]
+fn a_limited_key_is_only_accepted_inside_its_window() {
+    let mut flash = MockFlash::default();
+    let (mut store, _) = block_on(KeyStore::new(&mut flash));
+    block_on(store.add(&mut flash, key(1), KeyInfo::default())).unwrap();
+    block_on(store.add(&mut flash, key(2), KeyInfo::default())).unwrap();
+    let week = Validity { not_before: 1_700_000_000, not_after: 1_700_604_800 };
+    block_on(store.set_validity(&mut flash, key(1), week)).unwrap();
+    assert!(!block_on(store.set_validity(&mut flash, key(9), week)).unwrap());
+
+    let perm = Ok(key(1)[0]);
+    let schedules = no_schedules();
+    assert_eq!(store.authorize(&key(1), Some(1_699_999_999), &schedules), Err(AccessDenied::NotValid));
+    assert_eq!(store.authorize(&key(1), Some(1_700_000_000), &schedules), perm);
+    assert_eq!(store.authorize(&key(1), Some(1_700_604_800), &schedules), perm);
+    assert_eq!(store.authorize(&key(1), Some(1_700_604_801), &schedules), Err(AccessDenied::NotValid));
+    // Unknown time: only the unlimited key gets in
+    assert_eq!(store.authorize(&key(1), None, &schedules), Err(AccessDenied::TimeUnsynced));
+    assert_eq!(store.authorize(&key(2), None, &schedules), perm);
+    assert_eq!(store.authorize(&key(9), None, &schedules), Err(AccessDenied::UnknownKey));
+    // Still listed, so an admin can find and remove it
+    assert_eq!(store.lookup(&key(1)), key(1)[0]);
+
+    // An open end
+    let from = Validity { not_before: 1_700_000_000, not_after: 0 };
+    block_on(store.set_validity(&mut flash, key(1), from)).unwrap();
+    assert_eq!(store.authorize(&key(1), Some(u32::MAX as u64 + 1), &schedules), perm);
+    let (store, _) = block_on(KeyStore::new(flash.clone()));
+    assert_eq!(store.get_validity(0), Some(from));
+}
+
+#[test]
+fn a_scheduled_key_is_only_accepted_inside_its_windows() {
+    let mut flash = MockFlash::default();
+    let (mut schedules, _) = block_on(ScheduleStore::new(&mut flash));
+    // Monday to Friday 07:00-19:00, Saturday 09:00-12:00
+    let staff = Schedule {
+        windows: [
+            Window { days: 0x1f, start: 7 * 60, end: 19 * 60 },
+            Window { days: 0x20, start: 9 * 60, end: 12 * 60 },
+            Window::default(),
+            Window::default(),
+        ],
+    };
+    assert!(block_on(schedules.set(&mut flash, 3, staff)).unwrap());
+    assert!(!block_on(schedules.set(&mut flash, 33, staff)).unwrap());
+    let mut keys_flash = MockFlash::default();
+    let (mut store, _) = block_on(KeyStore::new(&mut keys_flash));
+    block_on(store.add(&mut keys_flash, key(1), KeyInfo::default())).unwrap();
+    assert!(block_on(store.set_schedule(&mut keys_flash, key(1), 3)).unwrap());
+
+    // 2024-01-01 was a Monday
+    let monday = 1_704_067_200;
+    let at = |day: u64, hour: u64, minute: u64| Some(monday + day * 86_400 + hour * 3_600 + minute * 60);
+    let perm = Ok(key(1)[0]);
+    assert_eq!(store.authorize(&key(1), at(0, 7, 0), &schedules), perm);
+    assert_eq!(store.authorize(&key(1), at(4, 18, 59), &schedules), perm);
+    assert_eq!(store.authorize(&key(1), at(0, 6, 59), &schedules), Err(AccessDenied::OutsideSchedule));
+    assert_eq!(store.authorize(&key(1), at(2, 19, 0), &schedules), Err(AccessDenied::OutsideSchedule));
+    assert_eq!(store.authorize(&key(1), at(5, 10, 0), &schedules), perm);
+    assert_eq!(store.authorize(&key(1), at(5, 13, 0), &schedules), Err(AccessDenied::OutsideSchedule));
+    assert_eq!(store.authorize(&key(1), at(6, 10, 0), &schedules), Err(AccessDenied::OutsideSchedule));
+    assert_eq!(store.authorize(&key(1), None, &schedules), Err(AccessDenied::TimeUnsynced));
+
+    // Both survive a restart
+    let (reloaded, _) = block_on(ScheduleStore::new(flash.clone()));
+    assert_eq!(reloaded.get(3), Some(&staff));
+    let (store, _) = block_on(KeyStore::new(keys_flash.clone()));
+    assert_eq!(store.schedule(&key(1)), Some(3));
+
+    // A deleted profile locks its keys out
+    block_on(schedules.set(&mut flash, 3, Schedule::default())).unwrap();
+    let (reloaded, _) = block_on(ScheduleStore::new(flash.clone()));
+    assert_eq!(reloaded.get(3), None);
+    assert_eq!(store.authorize(&key(1), at(0, 8, 0), &reloaded), Err(AccessDenied::OutsideSchedule));
+}
+
+#[test]
+fn schedule_windows_follow_the_utc_offset() {
+    let mut flash = MockFlash::default();
+    let (mut schedules, _) = block_on(ScheduleStore::new(&mut flash));
+    // Monday 07:00-19:00 local time
+    let monday_only = Schedule {
+        windows: [Window { days: 0x01, start: 7 * 60, end: 19 * 60 }, Window::default(), Window::default(), Window::default()],
+    };
+    block_on(schedules.set(&mut flash, 1, monday_only)).unwrap();
+    let mut keys_flash = MockFlash::default();
+    let (mut store, _) = block_on(KeyStore::new(&mut keys_flash));
+    block_on(store.add(&mut keys_flash, key(1), KeyInfo::default())).unwrap();
+    block_on(store.set_schedule(&mut keys_flash, key(1), 1)).unwrap();
+
+    // 2024-01-01 was a Monday
+    let monday = 1_704_067_200;
+    let utc = |day: u64, hour: u64, minute: u64| Some(monday + day * 86_400 + hour * 3_600 + minute * 60);
+    let perm = Ok(key(1)[0]);
+    // UTC+2: 05:00 UTC is 07:00 local, 17:00 UTC is 19:00 local
+    schedules.set_utc_offset(schedule::utc_offset_from_config(120).unwrap());
+    assert_eq!(store.authorize(&key(1), utc(0, 5, 0), &schedules), perm);
+    assert_eq!(store.authorize(&key(1), utc(0, 4, 59), &schedules), Err(AccessDenied::OutsideSchedule));
+    assert_eq!(store.authorize(&key(1), utc(0, 17, 0), &schedules), Err(AccessDenied::OutsideSchedule));
+    // UTC-5: the window runs 12:00 UTC to midnight UTC
+    schedules.set_utc_offset(schedule::utc_offset_from_config(-300i32 as u32).unwrap());
+    assert_eq!(store.authorize(&key(1), utc(0, 11, 59), &schedules), Err(AccessDenied::OutsideSchedule));
+    assert_eq!(store.authorize(&key(1), utc(0, 12, 0), &schedules), perm);
+    assert_eq!(store.authorize(&key(1), utc(0, 23, 59), &schedules), perm);
+    assert_eq!(store.authorize(&key(1), utc(1, 0, 0), &schedules), Err(AccessDenied::OutsideSchedule));
+    // Out of range offsets are not accepted
+    assert_eq!(schedule::utc_offset_from_config(841), None);
+    assert_eq!(schedule::utc_offset_from_config(-721i32 as u32), None);
+}
+
+#[test]
+fn a_use_limited_key_is_refused_once_used_up() {
+    let mut flash = MockFlash::default();
+    let (mut store, _) = block_on(KeyStore::new(&mut flash));
+    block_on(store.add(&mut flash, key(1), KeyInfo::default())).unwrap();
+    block_on(store.add(&mut flash, key(2), KeyInfo::default())).unwrap();
+    assert_eq!(store.uses(&key(1)), Some(UNLIMITED_USES));
+    assert_eq!(block_on(store.record_use(&mut flash, key(1))).unwrap(), None);
+    assert!(block_on(store.set_uses(&mut flash, key(1), 2)).unwrap());
+
+    let schedules = no_schedules();
+    assert_eq!(block_on(store.record_use(&mut flash, key(1))).unwrap(), Some(1));
+    // The count survives a restart
+    let (mut store, _) = block_on(KeyStore::new(flash.clone()));
+    assert_eq!(store.uses(&key(1)), Some(1));
+    assert_eq!(store.authorize(&key(1), None, &schedules), Ok(key(1)[0]));
+    assert_eq!(block_on(store.record_use(&mut flash, key(1))).unwrap(), Some(0));
+    assert_eq!(store.authorize(&key(1), None, &schedules), Err(AccessDenied::UsedUp));
+    assert_eq!(block_on(store.record_use(&mut flash, key(1))).unwrap(), Some(0));
+
+    // Disabled, not deleted: still listed and can be given new uses
+    assert_eq!(reload(&flash), [key(1), key(2)]);
+    block_on(store.set_uses(&mut flash, key(1), UNLIMITED_USES)).unwrap();
+    assert_eq!(store.authorize(&key(1), None, &schedules), Ok(key(1)[0]));
+    assert_eq!(store.uses(&key(2)), Some(UNLIMITED_USES));
+}
+
+#[test]
+fn key_info_is_kept_per_key() {
+    let label = |text: &str| {
+        let mut label = [0u8; LABEL_LEN];
+        label[..text.len()].copy_from_slice(text.as_bytes());
+        label
+    };
+    let mut flash = MockFlash::default();
+    let (mut store, _) = block_on(KeyStore::new(&mut flash));
+    block_on(store.add(&mut flash, key(1), KeyInfo::new(None, None))).unwrap();
+    block_on(store.add(&mut flash, key(2), KeyInfo::new(Some(1_700_000_000), Some(&key(1))))).unwrap();
+    assert!(block_on(store.set_label(&mut flash, key(2), label("Cleaner Anna"))).unwrap());
+    assert!(!block_on(store.set_label(&mut flash, key(9), label("Nobody"))).unwrap());
+
+    let (store, _) = block_on(KeyStore::new(flash.clone()));
+    let info = block_on(store.info(&mut flash, &key(2))).unwrap().unwrap();
+    assert_eq!(info.label(), Some("Cleaner Anna"));
+    assert_eq!(info.created, 1_700_000_000);
+    assert_eq!(info.creator, fingerprint(&key(1)));
+    // Permission bits do not change the fingerprint
+    let mut plain = key(1);
+    plain[0] &= 0x03;
+    assert_eq!(fingerprint(&plain), fingerprint(&key(1)));
+    assert_eq!(block_on(store.info(&mut flash, &key(1))).unwrap(), Some(KeyInfo::default()));
+    assert_eq!(block_on(store.info(&mut flash, &key(9))).unwrap(), None);
+
+    // A key reusing the ID of a deleted one does not inherit its label
+    let (mut store, _) = block_on(KeyStore::new(flash.clone()));
+    block_on(store.del(&mut flash, key(2))).unwrap();
+    block_on(store.add(&mut flash, key(3), KeyInfo::new(None, None))).unwrap();
+    assert_eq!(block_on(store.info(&mut flash, &key(3))).unwrap().unwrap().label(), Some(""));
+}
+
+#[test]
+fn schedule_windows_out_of_range_are_rejected() {
+    let window = |days: u8, start: u16, end: u16| {
+        let mut bytes = Schedule::default().to_bytes();
+        bytes[0] = days;
+        bytes[1..3].copy_from_slice(&start.to_le_bytes());
+        bytes[3..5].copy_from_slice(&end.to_le_bytes());
+        Schedule::from_bytes(&bytes)
+    };
+    assert!(window(0x7f, 0, 1440).is_some());
+    assert!(window(0x7f, 600, 600).is_none());
+    assert!(window(0x7f, 0, 1441).is_none());
+    assert!(window(0x80, 0, 60).is_none());
+    assert!(Schedule::from_bytes(&[0; 19]).is_none());
+}
+
+#[test]
+fn power_loss_during_an_edit_keeps_the_store_consistent() {
+    let mut rng = Rng(0x2545_f491);
+    let mut flash = MockFlash::default();
+    let (mut store, _) = block_on(KeyStore::new(&mut flash));
+    for n in 1..=20 {
+        block_on(store.add(&mut flash, key(n), KeyInfo::default())).unwrap();
+    }
+
+    for step in 0..200u32 {
+        // Alternate deleting a stored key and adding a new one
+        let n = 1 + rng.below(20) as u8;
+        let add = step % 2 == 1;
+        let edit = |store: &mut KeyStore, flash: &mut MockFlash| {
+            let target = if add { key(100 + n) } else { key(n) };
+            if add {
+                block_on(store.add(flash, target, KeyInfo::default()))
+            } else {
+                block_on(store.del(flash, target))
+            }
+        };
+
+        // Size the edit on a copy, then replay it with the power cut somewhere inside
+        let before = reload(&flash);
+        let mut sizing = flash.clone();
+        let (mut copy, _) = block_on(KeyStore::new(&mut sizing));
+        let start = sizing.bytes_written();
+        edit(&mut copy, &mut sizing).unwrap();
+        let size = sizing.bytes_written() - start;
+        let after = reload(&sizing);
+        if size == 0 {
+            continue;
+        }
+
+        let mut torn = flash.clone();
+        torn.cut_power_after(rng.below(size));
+        let (mut crashed, _) = block_on(KeyStore::new(&mut torn));
+        assert!(edit(&mut crashed, &mut torn).is_err());
+        torn.restore_power();
+        let loaded = reload(&torn);
+        assert!(loaded == before || loaded == after, "step {}: {:?} keys after the power loss", step, loaded.len());
+
+        // Carry on from the completed edit
+        flash = sizing;
+        store = copy;
+    }
+    assert_eq!(reload(&flash).len(), store.len());
+}
@@ -0,0 +1,36 @@
+//! Authenticate lockout and the auth log entries it lets through
+//!
+//! cargo +stable simtest
+#![cfg(feature = "std")]
+
+use blue_gate::lockout::AuthLimiter;
+use embassy_time::{Duration, Instant};
+
+const PEER: [u8; 6] = [1, 2, 3, 4, 5, 6];
+
+#[test]
+fn rejections_during_a_lockout_are_logged_once() {
+    let mut limiter = AuthLimiter::new();
+    let at = Instant::from_millis;
+    for ms in [0, 10, 20] {
+        assert_eq!(limiter.check(&PEER, at(ms)), None);
+        limiter.record_failure(&PEER, at(ms));
+    }
+    // Third failure: locked out for 2 s
+    let logged: Vec<bool> = (0..100)
+        .map(|n| {
+            let now = at(100 + n * 10);
+            let remaining = limiter.check(&PEER, now).unwrap();
+            limiter.log_rejection(now, remaining)
+        })
+        .collect();
+    assert_eq!(logged.iter().filter(|&&logged| logged).count(), 1);
+    assert!(logged[0]);
+
+    // A failure once the lockout ended starts a longer one, which is logged again
+    assert_eq!(limiter.check(&PEER, at(2_020)), None);
+    limiter.record_failure(&PEER, at(2_020));
+    let remaining = limiter.check(&PEER, at(2_030)).unwrap();
+    assert!(limiter.log_rejection(at(2_030), remaining));
+    assert!(!limiter.log_rejection(at(2_040), remaining - Duration::from_millis(10)));
+}
@@ -0,0 +1,541 @@
+//! Gate sequence scenarios replayed in virtual time
+//!
+//! Every scenario lists the exact relay edges (ms, on/off) for the lamp and both
+//! doors, so a change in sequencing or timing shows up as a diff here.
+//!
+//! cargo +stable simtest
+#![cfg(feature = "std")]
+
+use blue_gate::sim::{Relay, Simulation, Stimulus};
+use blue_gate::types::{
+    ControlMode, Door, DoorConfig, DriveMode, FsmCommand, GateConfig, LampTimings, DEFAULT_CONTROL_ACTIONS,
+};
+use embassy_time::Duration;
+
+/// Short timings so the edges are easy to follow:
+/// - lamp 1 s ahead of movement
+/// - left opens first, right closes first (500 ms offset)
+/// - 2 s movements, autoclose after 5 s
+/// - pedestrian open drives the left door for 1 s (half way)
+/// - an obstacle active for over 10 s faults the next close
+/// - 100 ms relay dead time when reversing (spent within the 1 s prestart)
+fn config(autoclose: bool, control_mode: ControlMode, limit_switches: bool) -> GateConfig {
+    GateConfig {
+        left_door: DoorConfig::new(
+            Duration::from_millis(0),
+            Duration::from_millis(500),
+            Duration::from_millis(2000),
+            Duration::from_millis(2000),
+        ),
+        right_door: DoorConfig::new(
+            Duration::from_millis(500),
+            Duration::from_millis(0),
+            Duration::from_millis(2000),
+            Duration::from_millis(2000),
+        ),
+        autoclose_delay: if autoclose { Some(Duration::from_millis(5000)) } else { None },
+        lamp_prestart: Duration::from_millis(1000),
+        control_mode,
+        control_actions: DEFAULT_CONTROL_ACTIONS,
+        pedestrian_door: Door::Left,
+        pedestrian_travel: Duration::from_millis(1000),
+        limit_switches,
+        obstacle_stuck_after: Some(Duration::from_millis(10000)),
+        drive_mode: DriveMode::Timed,
+    }
+}
+
+/// Lamp held on during the prestart and movement, so its edges follow the
+/// sequence; fault codes keep their flash pattern
+fn lamp_timings() -> LampTimings {
+    LampTimings {
+        blink_off: Duration::from_millis(0),
+        prestart_off: Duration::from_millis(0),
+        ..LampTimings::default()
+    }
+}
+
+enum Input {
+    /// Control pulse starting at this time
+    Pulse(u64),
+    /// Pedestrian input pulse starting at this time
+    Pedestrian(u64),
+    At(u64, Stimulus),
+}
+
+struct Scenario {
+    name: &'static str,
+    autoclose: bool,
+    mode: ControlMode,
+    inputs: &'static [Input],
+    duration: u64,
+    lamp: &'static [(u64, bool)],
+    left_open: &'static [(u64, bool)],
+    left_close: &'static [(u64, bool)],
+    right_open: &'static [(u64, bool)],
+    right_close: &'static [(u64, bool)],
+}
+
+// A control pulse at T is accepted once it held for the 100 ms debounce, so the
+// FSM reacts at T + 100 (gesture recognition is off in the simulation).
+// Obstacle detection is reported at once, its clearing after the debounce.
+const SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "control pulse opens, autoclose closes",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[Input::Pulse(1000)],
+        duration: 15_000,
+        // opening 1100, open 4600, autoclose 9600, closed 13100
+        lamp: &[(1100, true), (4600, false), (9600, true), (13100, false)],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[(11100, true), (13100, false)],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[(10600, true), (12600, false)],
+    },
+    Scenario {
+        name: "open command opens like a control pulse",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[Input::At(1000, Stimulus::Command(FsmCommand::Open))],
+        duration: 15_000,
+        lamp: &[(1000, true), (4500, false), (9500, true), (13000, false)],
+        left_open: &[(2000, true), (4000, false)],
+        left_close: &[(11000, true), (13000, false)],
+        right_open: &[(2500, true), (4500, false)],
+        right_close: &[(10500, true), (12500, false)],
+    },
+    Scenario {
+        name: "control pulse while open restarts autoclose",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[Input::Pulse(1000), Input::Pulse(8000)],
+        duration: 18_000,
+        // pulse accepted at 8100, autoclose at 13100
+        lamp: &[(1100, true), (4600, false), (13100, true), (16600, false)],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[(14600, true), (16600, false)],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[(14100, true), (16100, false)],
+    },
+    Scenario {
+        name: "StopAutoClose keeps the gate open",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::At(6000, Stimulus::Command(FsmCommand::StopAutoClose)),
+        ],
+        duration: 30_000,
+        lamp: &[(1100, true), (4600, false)],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[],
+    },
+    Scenario {
+        name: "close command while open",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::At(6000, Stimulus::Command(FsmCommand::Close)),
+        ],
+        duration: 12_000,
+        lamp: &[(1100, true), (4600, false), (6000, true), (9500, false)],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[(7500, true), (9500, false)],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[(7000, true), (9000, false)],
+    },
+    Scenario {
+        name: "control pulses while open do not close without autoclose",
+        autoclose: false,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::Pulse(6000),
+            Input::Pulse(10000),
+            Input::Pulse(14000),
+        ],
+        duration: 20_000,
+        // Closing takes a Close command or a triple click
+        lamp: &[(1100, true), (4600, false)],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[],
+    },
+    Scenario {
+        name: "obstacle while closing reverses",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::At(11500, Stimulus::Obstacle(true)),
+            Input::At(12000, Stimulus::Obstacle(false)),
+        ],
+        duration: 26_000,
+        // closing 9600, reversed at 11500 with left at 80 % (closed 400 ms) and
+        // right at 55 % (closed 900 ms), opening 11500 reopens only that far,
+        // open 13900, queued obstacle events restart autoclose, closing 18900
+        lamp: &[(1100, true), (4600, false), (9600, true), (13900, false), (18900, true), (22400, false)],
+        left_open: &[(2100, true), (4100, false), (12500, true), (12900, false)],
+        left_close: &[(11100, true), (11500, false), (20400, true), (22400, false)],
+        right_open: &[(2600, true), (4600, false), (13000, true), (13900, false)],
+        right_close: &[(10600, true), (11500, false), (19900, true), (21900, false)],
+    },
+    Scenario {
+        name: "obstacle during the close prestart reverses",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::At(10000, Stimulus::Obstacle(true)),
+            Input::At(10200, Stimulus::Obstacle(false)),
+        ],
+        duration: 15_000,
+        // nothing moved yet: back to Open at 10000, the queued obstacle events
+        // restart autoclose (last at 10300)
+        lamp: &[(1100, true), (4600, false), (9600, true), (10000, false)],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[],
+    },
+    Scenario {
+        name: "open command while closing reverses",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::At(12000, Stimulus::Command(FsmCommand::Open)),
+        ],
+        duration: 17_000,
+        // left closed 900 ms, right 1400 ms: reopened for the same time
+        lamp: &[(1100, true), (4600, false), (9600, true), (14900, false)],
+        left_open: &[(2100, true), (4100, false), (13000, true), (13900, false)],
+        left_close: &[(11100, true), (12000, false)],
+        right_open: &[(2600, true), (4600, false), (13500, true), (14900, false)],
+        right_close: &[(10600, true), (12000, false)],
+    },
+    Scenario {
+        name: "stop while opening, open resumes",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::At(3000, Stimulus::Command(FsmCommand::Stop)),
+            Input::At(5000, Stimulus::Command(FsmCommand::Open)),
+        ],
+        duration: 13_000,
+        // left stopped at 45 %, right at 20 %: only the remaining travel is driven
+        lamp: &[(1100, true), (3000, false), (5000, true), (8100, false)],
+        left_open: &[(2100, true), (3000, false), (6000, true), (7100, false)],
+        left_close: &[],
+        right_open: &[(2600, true), (3000, false), (6500, true), (8100, false)],
+        right_close: &[],
+    },
+    Scenario {
+        name: "stop during the open prestart returns to closed",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::At(1500, Stimulus::Command(FsmCommand::Stop)),
+            Input::Pulse(3000),
+        ],
+        duration: 8_000,
+        lamp: &[(1100, true), (1500, false), (3100, true), (6600, false)],
+        left_open: &[(4100, true), (6100, false)],
+        left_close: &[],
+        right_open: &[(4600, true), (6600, false)],
+        right_close: &[],
+    },
+    Scenario {
+        name: "stop during the prestart of a reversal leaves the doors part way",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::At(12000, Stimulus::Command(FsmCommand::Open)),
+            Input::At(12500, Stimulus::Command(FsmCommand::Stop)),
+            Input::At(14000, Stimulus::Command(FsmCommand::Close)),
+        ],
+        duration: 19_000,
+        // reversed with left closed 900 ms and right 1400 ms, stopped before
+        // reopening: not closed, so Close drives the remaining 1100 and 600 ms
+        lamp: &[(1100, true), (4600, false), (9600, true), (12500, false), (14000, true), (16600, false)],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[(11100, true), (12000, false), (15500, true), (16600, false)],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[(10600, true), (12000, false), (15000, true), (15600, false)],
+    },
+    Scenario {
+        name: "stop while open holds, pulse resumes autoclose",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::At(6000, Stimulus::Command(FsmCommand::Stop)),
+            Input::Pulse(20000),
+        ],
+        duration: 30_000,
+        // held 6000..20100, autoclose 25100
+        lamp: &[(1100, true), (4600, false), (25100, true), (28600, false)],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[(26600, true), (28600, false)],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[(26100, true), (28100, false)],
+    },
+    Scenario {
+        name: "stop while closing, close resumes",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::At(11500, Stimulus::Command(FsmCommand::Stop)),
+            Input::At(13000, Stimulus::Command(FsmCommand::Close)),
+        ],
+        duration: 17_000,
+        // left stopped at 80 %, right at 55 %
+        lamp: &[(1100, true), (4600, false), (9600, true), (11500, false), (13000, true), (16100, false)],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[(11100, true), (11500, false), (14500, true), (16100, false)],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[(10600, true), (11500, false), (14000, true), (15100, false)],
+    },
+    Scenario {
+        name: "StopAutoClose while opening applies once open",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::At(1000, Stimulus::Command(FsmCommand::Open)),
+            Input::At(1000, Stimulus::Command(FsmCommand::StopAutoClose)),
+        ],
+        duration: 15_000,
+        lamp: &[(1000, true), (4500, false)],
+        left_open: &[(2000, true), (4000, false)],
+        left_close: &[],
+        right_open: &[(2500, true), (4500, false)],
+        right_close: &[],
+    },
+    Scenario {
+        name: "step-by-step open, stop, close, stop, open",
+        autoclose: true,
+        mode: ControlMode::StepByStep,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::Pulse(3000),
+            Input::Pulse(5000),
+            Input::Pulse(7000),
+            Input::Pulse(9000),
+        ],
+        duration: 14_000,
+        // stopped at left 50 %, right 25 %; right closes fully (500 ms), left is
+        // stopped at 25 %; reopening drives left 1500 ms, right 2000 ms
+        lamp: &[(1100, true), (3100, false), (5100, true), (7100, false), (9100, true), (12600, false)],
+        left_open: &[(2100, true), (3100, false), (10100, true), (11600, false)],
+        left_close: &[(6600, true), (7100, false)],
+        right_open: &[(2600, true), (3100, false), (10600, true), (12600, false)],
+        right_close: &[(6100, true), (6600, false)],
+    },
+    Scenario {
+        name: "step-by-step pulse while open closes",
+        autoclose: true,
+        mode: ControlMode::StepByStep,
+        inputs: &[Input::Pulse(1000), Input::Pulse(6000)],
+        duration: 11_000,
+        lamp: &[(1100, true), (4600, false), (6100, true), (9600, false)],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[(7600, true), (9600, false)],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[(7100, true), (9100, false)],
+    },
+    Scenario {
+        name: "obstacle stuck for over 10 s faults the close",
+        autoclose: false,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::At(500, Stimulus::Obstacle(true)),
+            Input::Pulse(1000),
+            Input::At(12000, Stimulus::Command(FsmCommand::Close)),
+        ],
+        duration: 15000,
+        // SensorStuck: two flashes every 2.5 s, no relay moves
+        lamp: &[
+            (1100, true),
+            (4600, false),
+            (12000, true),
+            (12250, false),
+            (12500, true),
+            (12750, false),
+            (14500, true),
+            (14750, false),
+            (15000, true),
+        ],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[],
+    },
+    Scenario {
+        name: "pedestrian pulse opens the left door half way",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[Input::Pedestrian(1000)],
+        duration: 12_000,
+        lamp: &[(1100, true), (3100, false), (8100, true), (10600, false)],
+        left_open: &[(2100, true), (3100, false)],
+        left_close: &[(9600, true), (10600, false)],
+        right_open: &[],
+        right_close: &[],
+    },
+    Scenario {
+        name: "open command while pedestrian-open opens fully",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::At(1000, Stimulus::Command(FsmCommand::OpenPedestrian)),
+            Input::At(4000, Stimulus::Command(FsmCommand::Open)),
+        ],
+        duration: 13_000,
+        lamp: &[(1000, true), (3000, false), (4000, true), (7500, false), (12500, true)],
+        left_open: &[(2000, true), (3000, false), (5000, true), (6000, false)],
+        left_close: &[],
+        right_open: &[(5500, true), (7500, false)],
+        right_close: &[],
+    },
+    Scenario {
+        name: "obstacle during pedestrian close reopens to the pedestrian position",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pedestrian(1000),
+            Input::At(10000, Stimulus::Obstacle(true)),
+            Input::At(10200, Stimulus::Obstacle(false)),
+        ],
+        duration: 20_000,
+        lamp: &[(1100, true), (3100, false), (8100, true), (11400, false), (16400, true), (18900, false)],
+        left_open: &[(2100, true), (3100, false), (11000, true), (11400, false)],
+        left_close: &[(9600, true), (10000, false), (17900, true), (18900, false)],
+        right_open: &[],
+        right_close: &[],
+    },
+];
+
+// Same timings with limit switches fitted: the 2 s durations become travel timeouts.
+// Limit switches are debounced like the control input, a trip at T stops the relay at T + 100.
+const LIMIT_SCENARIOS: &[Scenario] = &[
+    Scenario {
+        name: "limit switches end each travel",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::At(0, Stimulus::CloseLimit(Door::Left, true)),
+            Input::At(0, Stimulus::CloseLimit(Door::Right, true)),
+            Input::Pulse(1000),
+            Input::At(2200, Stimulus::CloseLimit(Door::Left, false)),
+            Input::At(2700, Stimulus::CloseLimit(Door::Right, false)),
+            Input::At(3500, Stimulus::OpenLimit(Door::Left, true)),
+            Input::At(4200, Stimulus::OpenLimit(Door::Right, true)),
+            Input::At(10400, Stimulus::OpenLimit(Door::Right, false)),
+            Input::At(10900, Stimulus::OpenLimit(Door::Left, false)),
+            Input::At(11500, Stimulus::CloseLimit(Door::Right, true)),
+            Input::At(12000, Stimulus::CloseLimit(Door::Left, true)),
+        ],
+        duration: 13_000,
+        // left opens in 1.5 s, right in 1.7 s; autoclose 5 s after 4300
+        lamp: &[(1100, true), (4300, false), (9300, true), (12100, false)],
+        left_open: &[(2100, true), (3600, false)],
+        left_close: &[(10800, true), (12100, false)],
+        right_open: &[(2600, true), (4300, false)],
+        right_close: &[(10300, true), (11600, false)],
+    },
+    Scenario {
+        name: "missed limit switch faults until cleared",
+        autoclose: true,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::Pulse(1000),
+            Input::At(5000, Stimulus::Command(FsmCommand::Close)),
+            Input::At(6000, Stimulus::Command(FsmCommand::ClearFault)),
+            Input::At(6500, Stimulus::Command(FsmCommand::Close)),
+            Input::At(8500, Stimulus::CloseLimit(Door::Right, true)),
+            Input::At(9000, Stimulus::CloseLimit(Door::Left, true)),
+        ],
+        duration: 10000,
+        // both doors time out after 2 s: Fault at 4600 flashes once every 2 s,
+        // Close is ignored until the fault is cleared (held open, no autoclose)
+        lamp: &[(1100, true), (4850, false), (6500, true), (9100, false)],
+        left_open: &[(2100, true), (4100, false)],
+        left_close: &[(8000, true), (9100, false)],
+        right_open: &[(2600, true), (4600, false)],
+        right_close: &[(7500, true), (8600, false)],
+    },
+    Scenario {
+        name: "limit switch active at start-up sets the position",
+        autoclose: false,
+        mode: ControlMode::Standard,
+        inputs: &[
+            Input::At(0, Stimulus::OpenLimit(Door::Left, true)),
+            Input::Pulse(1000),
+            Input::At(4000, Stimulus::OpenLimit(Door::Right, true)),
+        ],
+        duration: 5000,
+        // left is already open, only right moves
+        lamp: &[(1100, true), (4100, false)],
+        left_open: &[],
+        left_close: &[],
+        right_open: &[(2600, true), (4100, false)],
+        right_close: &[],
+    },
+];
+
+fn run_scenarios(scenarios: &[Scenario], limit_switches: bool) {
+    let mut failures = Vec::new();
+    for scenario in scenarios {
+        let mut sim =
+            Simulation::new(config(scenario.autoclose, scenario.mode, limit_switches))
+                .relay_dead_time(Duration::from_millis(100))
+                .lamp_timings(lamp_timings());
+        for input in scenario.inputs {
+            sim = match *input {
+                Input::Pulse(at) => sim.pulse(at),
+                Input::Pedestrian(at) => sim.pedestrian_pulse(at),
+                Input::At(at, stimulus) => sim.at(at, stimulus),
+            };
+        }
+        let trace = sim.run(scenario.duration);
+
+        let expected = [
+            (Relay::Lamp, scenario.lamp),
+            (Relay::LeftOpen, scenario.left_open),
+            (Relay::LeftClose, scenario.left_close),
+            (Relay::RightOpen, scenario.right_open),
+            (Relay::RightClose, scenario.right_close),
+        ];
+        for (relay, edges) in expected {
+            if trace.edges(relay) != edges {
+                failures.push(format!(
+                    "{}: {:?}\n  expected {:?}\n  got      {:?}",
+                    scenario.name,
+                    relay,
+                    edges,
+                    trace.edges(relay)
+                ));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn scenarios() {
+    run_scenarios(SCENARIOS, false);
+}
+
+#[test]
+fn limit_switch_scenarios() {
+    run_scenarios(LIMIT_SCENARIOS, true);
+}
@@ -0,0 +1,266 @@
+//! Smoke tests for the host simulation harness
+//!
+//! cargo +stable simtest
+#![cfg(feature = "std")]
+
+use blue_gate::sim::{Relay, Simulation, Stimulus};
+use blue_gate::types::{ControlAction, Door, DriveMode, FsmCommand, GateConfig, GateState, GpiConfig, GpoCommand, StateChange};
+use embassy_time::{Duration, Instant};
+
+#[test]
+fn idle_gate_keeps_outputs_off() {
+    let trace = Simulation::new(GateConfig::default()).run(5_000);
+    for relay in Relay::ALL {
+        assert!(trace.edges(relay).is_empty(), "{:?} changed: {:?}", relay, trace.edges(relay));
+    }
+}
+
+#[test]
+fn control_pulse_runs_open_relays() {
+    let trace = Simulation::new(GateConfig::default()).pulse(1_000).run(20_000);
+    for relay in [Relay::Lamp, Relay::LeftOpen, Relay::RightOpen] {
+        assert!(!trace.intervals(relay).is_empty(), "{:?} never switched on", relay);
+    }
+    assert!(trace.edges(Relay::LeftClose).is_empty());
+    assert!(trace.edges(Relay::RightClose).is_empty());
+}
+
+#[test]
+fn open_command_matches_control_pulse() {
+    let by_command = Simulation::new(GateConfig::default())
+        .at(1_000, Stimulus::Command(FsmCommand::Open))
+        .run(20_000);
+    assert!(by_command.is_high_at(Relay::LeftOpen, 3_000));
+    assert!(by_command.is_high_at(Relay::RightOpen, 3_000));
+}
+
+#[test]
+fn lamp_fast_blinks_for_the_prestart_then_blinks() {
+    let trace = Simulation::new(GateConfig::default()).pulse(1_000).run(6_000);
+    // 250/250 ms during the 1 s prestart, then 1.5 s on, 0.5 s off
+    assert_eq!(
+        trace.intervals(Relay::Lamp)[..4],
+        [(1_100, Some(1_350)), (1_600, Some(1_850)), (2_100, Some(3_600)), (4_100, Some(5_600))]
+    );
+}
+
+#[test]
+fn short_pulse_is_seen_between_edges() {
+    let trace = Simulation::new(GateConfig::default())
+        .input_debounce(GpiConfig {
+            control_debounce: Duration::from_millis(20),
+            ..GpiConfig::default()
+        })
+        .at(1_013, Stimulus::Control(true))
+        .at(1_043, Stimulus::Control(false))
+        .run(3_000);
+    // Accepted 20 ms after the edge, the lamp prestart starts right away
+    assert_eq!(trace.edges(Relay::Lamp)[0], (1_033, true));
+}
+
+#[test]
+fn bouncing_control_input_is_ignored() {
+    let trace = Simulation::new(GateConfig::default())
+        .at(1_000, Stimulus::Control(true))
+        .at(1_060, Stimulus::Control(false))
+        .at(1_100, Stimulus::Control(true))
+        .at(1_180, Stimulus::Control(false))
+        .run(3_000);
+    // Never held for the 100 ms debounce
+    assert!(trace.edges(Relay::Lamp).is_empty());
+}
+
+#[test]
+fn close_is_refused_while_the_obstacle_is_present() {
+    let trace = Simulation::new(GateConfig::default())
+        .at(1_000, Stimulus::Command(FsmCommand::Open))
+        .at(19_000, Stimulus::Obstacle(true))
+        .at(20_000, Stimulus::Command(FsmCommand::Close))
+        .at(22_000, Stimulus::Obstacle(false))
+        .at(23_000, Stimulus::Command(FsmCommand::Close))
+        .run(26_000);
+    // The refused close never starts the prestart, the one after the obstacle clears does
+    let close_started = trace.intervals(Relay::LeftClose).first().map(|(on, _)| *on);
+    assert_eq!(close_started, Some(24_000));
+    assert!(!trace.is_high_at(Relay::Lamp, 20_500));
+}
+
+#[test]
+fn obstacle_spikes_shorter_than_the_detection_debounce_are_ignored() {
+    let trace = Simulation::new(GateConfig::default())
+        .input_debounce(GpiConfig::default())
+        .at(1_000, Stimulus::Command(FsmCommand::Open))
+        .at(20_000, Stimulus::Command(FsmCommand::Close))
+        .at(23_000, Stimulus::Obstacle(true))
+        .at(23_010, Stimulus::Obstacle(false))
+        .at(25_000, Stimulus::Obstacle(true))
+        .run(26_000);
+    // The 10 ms spike does not stop the close, the held obstacle does after 20 ms
+    assert_eq!(trace.intervals(Relay::LeftClose), [(21_000, Some(25_020))]);
+}
+
+#[test]
+fn double_click_is_mapped_through_the_gesture_table() {
+    let config = GateConfig {
+        control_actions: [
+            ControlAction::None,
+            ControlAction::None,
+            ControlAction::Command(FsmCommand::Open),
+            ControlAction::None,
+        ],
+        ..GateConfig::default()
+    };
+    let trace = Simulation::new(config)
+        .control_gestures(Duration::from_secs(2), Duration::from_millis(500))
+        .pulse(1_000)
+        .pulse(5_000)
+        .pulse(5_400)
+        .run(8_000);
+    // The single click does nothing; the double click is known once no third
+    // click followed within 500 ms of its release (accepted at 5_700)
+    assert_eq!(trace.edges(Relay::Lamp)[0], (6_200, true));
+}
+
+#[test]
+fn long_press_opens_and_triple_click_closes() {
+    let trace = Simulation::new(GateConfig::default())
+        .control_gestures(Duration::from_secs(2), Duration::from_millis(500))
+        .at(1_000, Stimulus::Control(true))
+        .at(4_000, Stimulus::Control(false))
+        .pulse(22_000)
+        .pulse(22_400)
+        .pulse(22_800)
+        .run(26_000);
+    // Long press reported 2 s after the press, while still held
+    assert_eq!(trace.edges(Relay::Lamp)[0], (3_000, true));
+    // Third release accepted at 23_100, close relays after the 1 s prestart
+    let close_started = trace.intervals(Relay::LeftClose).first().map(|(on, _)| *on);
+    assert_eq!(close_started, Some(24_100));
+}
+
+#[test]
+fn inverted_inputs_are_honoured() {
+    let trace = Simulation::new(GateConfig::default())
+        .input_polarity(0)
+        .pulse(1_000)
+        .run(5_000);
+    assert!(trace.is_high_at(Relay::LeftOpen, 3_000));
+}
+
+#[test]
+fn inverted_outputs_are_honoured() {
+    let trace = Simulation::new(GateConfig::default())
+        .output_polarity(0x1f)
+        .pulse(1_000)
+        .run(5_000);
+    // Every output is driven to its inactive (high) level at start-up
+    assert_eq!(trace.edges(Relay::LeftOpen), [(0, true), (2_100, false)]);
+    assert_eq!(trace.edges(Relay::LeftClose), [(0, true)]);
+    assert_eq!(trace.edges(Relay::Lamp)[..2], [(0, true), (1_100, false)]);
+}
+
+#[test]
+fn invalid_config_flashes_fault_code() {
+    let mut config = GateConfig::default();
+    config.left_door.open_duration = Duration::from_millis(0);
+    let trace = Simulation::new(config).pulse(1_000).run(5_000);
+    // ConfigInvalid: four flashes from start-up, then a pause; pulses are ignored
+    assert_eq!(
+        trace.intervals(Relay::Lamp)[..5],
+        [
+            (0, Some(250)),
+            (500, Some(750)),
+            (1_000, Some(1_250)),
+            (1_500, Some(1_750)),
+            (3_500, Some(3_750)),
+        ]
+    );
+    assert!(trace.edges(Relay::LeftOpen).is_empty());
+    assert!(trace.edges(Relay::RightOpen).is_empty());
+}
+
+#[test]
+fn relay_interlock_faults_the_gate() {
+    let trace = Simulation::new(GateConfig::default())
+        .at(1_000, Stimulus::Gpo(GpoCommand::SetDoorOpen { door: Door::Left, active: true }))
+        .at(1_500, Stimulus::Gpo(GpoCommand::SetDoorClose { door: Door::Left, active: true }))
+        .run(5_000);
+    // Both relays are refused and the lamp flashes RelayInterlock (three flashes)
+    assert_eq!(trace.intervals(Relay::LeftOpen), [(1_000, Some(1_500))]);
+    assert!(trace.edges(Relay::LeftClose).is_empty());
+    assert_eq!(
+        trace.intervals(Relay::Lamp)[..4],
+        [(1_500, Some(1_750)), (2_000, Some(2_250)), (2_500, Some(2_750)), (4_500, Some(4_750))]
+    );
+}
+
+#[test]
+fn reversal_waits_for_the_dead_time() {
+    let trace = Simulation::new(GateConfig::default())
+        .at(1_000, Stimulus::Gpo(GpoCommand::SetDoorClose { door: Door::Left, active: true }))
+        .at(2_000, Stimulus::Gpo(GpoCommand::SetDoorClose { door: Door::Left, active: false }))
+        .at(2_100, Stimulus::Gpo(GpoCommand::SetDoorOpen { door: Door::Left, active: true }))
+        .at(3_000, Stimulus::Gpo(GpoCommand::SetDoorOpen { door: Door::Left, active: false }))
+        .at(3_100, Stimulus::Gpo(GpoCommand::SetDoorOpen { door: Door::Left, active: true }))
+        .run(5_000);
+    // 500 ms dead time after the close relay, none when the same direction restarts
+    assert_eq!(trace.intervals(Relay::LeftClose), [(1_000, Some(2_000))]);
+    assert_eq!(trace.intervals(Relay::LeftOpen), [(2_500, Some(3_000)), (3_100, None)]);
+}
+
+#[test]
+fn pulse_mode_emits_command_pulses() {
+    let config = GateConfig {
+        drive_mode: DriveMode::Pulse,
+        autoclose_delay: None,
+        ..GateConfig::default()
+    };
+    let trace = Simulation::new(config)
+        .pulse(1_000)
+        .at(3_000, Stimulus::Command(FsmCommand::Close))
+        .at(4_000, Stimulus::Command(FsmCommand::Stop))
+        .run(6_000);
+    // 500 ms pulses: open on left open, close on left close, stop on right open
+    assert_eq!(trace.intervals(Relay::LeftOpen), [(1_100, Some(1_600))]);
+    assert_eq!(trace.intervals(Relay::LeftClose), [(3_000, Some(3_500))]);
+    assert_eq!(trace.intervals(Relay::RightOpen), [(4_000, Some(4_500))]);
+    assert!(trace.edges(Relay::RightClose).is_empty());
+    assert!(trace.edges(Relay::Lamp).is_empty());
+}
+
+#[test]
+fn pulse_mode_does_not_pulse_while_the_obstacle_blocks_the_close() {
+    let config = GateConfig {
+        drive_mode: DriveMode::Pulse,
+        autoclose_delay: Some(Duration::from_secs(2)),
+        ..GateConfig::default()
+    };
+    let trace = Simulation::new(config)
+        .pulse(1_000)
+        .at(2_000, Stimulus::Obstacle(true))
+        .at(14_000, Stimulus::Obstacle(false))
+        .run(20_000);
+    // The held obstacle is reported every 3 s and restarts the autoclose each time; the
+    // autocloses at 4_000, 7_000, 10_000 and 13_000 are refused without any pulse and
+    // re-armed, the one 2 s after the clear closes
+    assert_eq!(trace.intervals(Relay::LeftOpen), [(1_100, Some(1_600))]);
+    assert_eq!(trace.intervals(Relay::LeftClose), [(16_100, Some(16_600))]);
+}
+
+#[test]
+fn state_changes_are_published_with_their_time() {
+    let trace = Simulation::new(GateConfig::default()).pulse(1_000).run(70_000);
+    let change = |from, to, at_ms| StateChange { from, to, at: Instant::from_millis(at_ms) };
+    // Opening once the pulse is accepted, open after the prestart, the 500 ms delay
+    // of the second door and its 15 s travel, closing when the 30 s autoclose expires
+    assert_eq!(
+        trace.states(),
+        [
+            change(GateState::Closed, GateState::Closed, 0),
+            change(GateState::Closed, GateState::Opening, 1_100),
+            change(GateState::Opening, GateState::Open, 17_600),
+            change(GateState::Open, GateState::Closing, 47_600),
+            change(GateState::Closing, GateState::Closed, 64_100),
+        ]
+    );
+}